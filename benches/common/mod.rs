@@ -0,0 +1,17 @@
+use std::collections::BTreeMap;
+
+use mp_psi::{crs_commit, crs_derive, crs_reveal, SessionCrs};
+
+/// Runs the coin toss of a two party session and returns the CRS of party 0.
+pub fn coin_toss() -> SessionCrs {
+    let (a_state, a_message) = crs_commit(0);
+    let (b_state, b_message) = crs_commit(1);
+    let (a_state, a_reveal) = crs_reveal(
+        a_state,
+        a_message.clone(),
+        BTreeMap::from([(1, b_message.clone())]),
+    )
+    .unwrap();
+    let (_, b_reveal) = crs_reveal(b_state, b_message, BTreeMap::from([(0, a_message)])).unwrap();
+    crs_derive(a_state, a_reveal, BTreeMap::from([(1, b_reveal)])).unwrap()
+}
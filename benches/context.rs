@@ -0,0 +1,112 @@
+//! Time saved by building the bfv parameters and the evaluator once per session instead of
+//! once per round.
+
+use std::collections::BTreeMap;
+
+use common::coin_toss;
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion};
+use mp_psi::{gen_keys, round1, round2, round3, PsiContext, PsiMode, PsiParameters, SessionCrs};
+
+mod common;
+
+/// Calls `f` with `shared`, or with a context rebuilt from its parameters like every round did
+/// before the context existed.
+fn with_context<T>(shared: &PsiContext, rebuild: bool, f: impl FnOnce(&PsiContext) -> T) -> T {
+    if rebuild {
+        f(&PsiContext::new(shared.psi_parameters().clone()))
+    } else {
+        f(shared)
+    }
+}
+
+/// Runs all rounds of a two party session, with both parties in this thread.
+fn two_party_session(psi_context: &PsiContext, rebuild: bool, crs: &SessionCrs) -> Vec<u32> {
+    let bit_vector = vec![1; psi_context.psi_parameters().ring_size()];
+
+    let (a_psi_keys, a_message_round1) = with_context(psi_context, rebuild, |c| {
+        gen_keys(c, crs, PsiMode::Intersection, 0)
+    });
+    let (b_psi_keys, b_message_round1) = with_context(psi_context, rebuild, |c| {
+        gen_keys(c, crs, PsiMode::Intersection, 1)
+    });
+
+    let (a_state_round2, a_message_round2) = with_context(psi_context, rebuild, |c| {
+        round1(
+            c,
+            &a_psi_keys,
+            a_message_round1.clone(),
+            BTreeMap::from([(1, b_message_round1.clone())]),
+            &bit_vector,
+        )
+        .unwrap()
+    });
+    let (b_state_round2, b_message_round2) = with_context(psi_context, rebuild, |c| {
+        round1(
+            c,
+            &b_psi_keys,
+            b_message_round1,
+            BTreeMap::from([(0, a_message_round1)]),
+            &bit_vector,
+        )
+        .unwrap()
+    });
+
+    let (a_state_round3, a_message_round3) = with_context(psi_context, rebuild, |c| {
+        round2(
+            c,
+            &a_psi_keys,
+            a_state_round2,
+            a_message_round2.clone(),
+            BTreeMap::from([(1, b_message_round2.clone())]),
+        )
+        .unwrap()
+    });
+    let (_, b_message_round3) = with_context(psi_context, rebuild, |c| {
+        round2(
+            c,
+            &b_psi_keys,
+            b_state_round2,
+            b_message_round2,
+            BTreeMap::from([(0, a_message_round2)]),
+        )
+        .unwrap()
+    });
+
+    with_context(psi_context, rebuild, |c| {
+        round3(
+            c,
+            a_state_round3,
+            a_message_round3,
+            BTreeMap::from([(1, b_message_round3)]),
+        )
+        .unwrap()
+    })
+}
+
+fn bench_context(c: &mut Criterion) {
+    let mut group = c.benchmark_group("context");
+    group.sample_size(10);
+    for preset in ["small", "medium", "large"] {
+        let psi_parameters = PsiParameters::preset(preset).unwrap();
+        group.bench_with_input(BenchmarkId::new("new", preset), &psi_parameters, |b, p| {
+            b.iter(|| PsiContext::new(p.clone()))
+        });
+    }
+    group.finish();
+
+    let mut group = c.benchmark_group("session");
+    group.sample_size(10);
+    let crs = coin_toss();
+    for preset in ["small", "medium"] {
+        let psi_context = PsiContext::new(PsiParameters::preset(preset).unwrap());
+        for (name, rebuild) in [("shared_context", false), ("context_per_round", true)] {
+            group.bench_with_input(BenchmarkId::new(name, preset), &rebuild, |b, rebuild| {
+                b.iter(|| two_party_session(&psi_context, *rebuild, &crs))
+            });
+        }
+    }
+    group.finish();
+}
+
+criterion_group!(benches, bench_context);
+criterion_main!(benches);
@@ -0,0 +1,203 @@
+//! Time of every protocol round of a two party session, by parameter preset and by number of
+//! ciphertext chunks in the bit vector.
+
+use std::collections::BTreeMap;
+
+use common::coin_toss;
+use criterion::{criterion_group, criterion_main, BatchSize, BenchmarkId, Criterion};
+use mp_psi::{
+    convert, gen_keys,
+    proto::{MessageRound2Proto, StateRound2Proto, StateRound3Proto},
+    round1, round2, round3, MessageRound1, MessageRound2, MessageRound3, PsiContext, PsiKeys,
+    PsiMode, PsiParameters, SessionCrs, StateRound2, StateRound3,
+};
+
+mod common;
+
+const PRESETS: [&str; 3] = ["small", "medium", "large"];
+const CHUNK_COUNTS: [usize; 4] = [1, 3, 16, 64];
+
+/// Inputs of every round of party 0. The states that the rounds consume are kept in proto form,
+/// so every iteration can convert a fresh copy outside the measurement.
+struct Session {
+    psi_context: PsiContext,
+    bit_vector: Vec<u32>,
+    psi_keys: PsiKeys,
+    messages_round1: (MessageRound1, MessageRound1),
+    state_round2: StateRound2Proto,
+    messages_round2: (MessageRound2, MessageRound2),
+    state_round3: StateRound3Proto,
+    messages_round3: (MessageRound3, MessageRound3),
+}
+
+impl Session {
+    fn new(psi_context: PsiContext, crs: &SessionCrs, chunk_count: usize) -> Session {
+        let params = psi_context.bfv_parameters();
+        let bit_vector = (0..psi_context.psi_parameters().ring_size() * chunk_count)
+            .map(|i| (i % 2) as u32)
+            .collect::<Vec<_>>();
+
+        let (a_psi_keys, a_message_round1) = gen_keys(&psi_context, crs, PsiMode::Intersection, 0);
+        let (b_psi_keys, b_message_round1) = gen_keys(&psi_context, crs, PsiMode::Intersection, 1);
+
+        let (a_state_round2, a_message_round2) = round1(
+            &psi_context,
+            &a_psi_keys,
+            a_message_round1.clone(),
+            BTreeMap::from([(1, b_message_round1.clone())]),
+            &bit_vector,
+        )
+        .unwrap();
+        let (b_state_round2, b_message_round2) = round1(
+            &psi_context,
+            &b_psi_keys,
+            b_message_round1.clone(),
+            BTreeMap::from([(0, a_message_round1.clone())]),
+            &bit_vector,
+        )
+        .unwrap();
+        let state_round2 = convert(&a_state_round2, params);
+
+        let (a_state_round3, a_message_round3) = round2(
+            &psi_context,
+            &a_psi_keys,
+            a_state_round2,
+            a_message_round2.clone(),
+            BTreeMap::from([(1, b_message_round2.clone())]),
+        )
+        .unwrap();
+        let (_, b_message_round3) = round2(
+            &psi_context,
+            &b_psi_keys,
+            b_state_round2,
+            b_message_round2.clone(),
+            BTreeMap::from([(0, a_message_round2.clone())]),
+        )
+        .unwrap();
+        let state_round3 = convert(&a_state_round3, params);
+
+        Session {
+            psi_context,
+            bit_vector,
+            psi_keys: a_psi_keys,
+            messages_round1: (a_message_round1, b_message_round1),
+            state_round2,
+            messages_round2: (a_message_round2, b_message_round2),
+            state_round3,
+            messages_round3: (a_message_round3, b_message_round3),
+        }
+    }
+}
+
+fn bench_keygen(c: &mut Criterion) {
+    let crs = coin_toss();
+    let mut group = c.benchmark_group("keygen");
+    group.sample_size(10);
+    for preset in PRESETS {
+        let psi_context = PsiContext::new(PsiParameters::preset(preset).unwrap());
+        group.bench_function(preset, |b| {
+            b.iter(|| gen_keys(&psi_context, &crs, PsiMode::Intersection, 0))
+        });
+    }
+    group.finish();
+}
+
+fn bench_rounds(c: &mut Criterion) {
+    let crs = coin_toss();
+    for preset in PRESETS {
+        for chunk_count in CHUNK_COUNTS {
+            let psi_context = PsiContext::new(PsiParameters::preset(preset).unwrap());
+            let session = Session::new(psi_context, &crs, chunk_count);
+            let psi_context = &session.psi_context;
+            let params = psi_context.bfv_parameters();
+            let id = BenchmarkId::new(preset, chunk_count);
+
+            let mut group = c.benchmark_group("round1");
+            group.sample_size(10);
+            group.bench_function(id.clone(), |b| {
+                let (own, other) = &session.messages_round1;
+                b.iter_batched(
+                    || (own.clone(), BTreeMap::from([(1, other.clone())])),
+                    |(own, others)| {
+                        round1(
+                            psi_context,
+                            &session.psi_keys,
+                            own,
+                            others,
+                            &session.bit_vector,
+                        )
+                        .unwrap()
+                    },
+                    BatchSize::LargeInput,
+                )
+            });
+            group.finish();
+
+            let mut group = c.benchmark_group("round2");
+            group.sample_size(10);
+            group.bench_function(id.clone(), |b| {
+                let (own, other) = &session.messages_round2;
+                b.iter_batched(
+                    || {
+                        (
+                            convert::<_, StateRound2>(&session.state_round2, params),
+                            own.clone(),
+                            BTreeMap::from([(1, other.clone())]),
+                        )
+                    },
+                    |(state, own, others)| {
+                        round2(psi_context, &session.psi_keys, state, own, others).unwrap()
+                    },
+                    BatchSize::LargeInput,
+                )
+            });
+            group.finish();
+
+            let mut group = c.benchmark_group("round3");
+            group.sample_size(10);
+            group.bench_function(id.clone(), |b| {
+                let (own, other) = &session.messages_round3;
+                b.iter_batched(
+                    || {
+                        (
+                            convert::<_, StateRound3>(&session.state_round3, params),
+                            own.clone(),
+                            BTreeMap::from([(1, other.clone())]),
+                        )
+                    },
+                    |(state, own, others)| round3(psi_context, state, own, others).unwrap(),
+                    BatchSize::LargeInput,
+                )
+            });
+            group.finish();
+
+            // the ciphertexts of round 2 are the messages that grow with the domain
+            let message = &session.messages_round2.0;
+            let frame =
+                bincode::serialize(&convert::<_, MessageRound2Proto>(message, params)).unwrap();
+            let mut group = c.benchmark_group("proto");
+            group.sample_size(10);
+            group.bench_function(
+                BenchmarkId::new(format!("serialize/{preset}"), chunk_count),
+                |b| {
+                    b.iter(|| {
+                        bincode::serialize(&convert::<_, MessageRound2Proto>(message, params))
+                    })
+                },
+            );
+            group.bench_function(
+                BenchmarkId::new(format!("deserialize/{preset}"), chunk_count),
+                |b| {
+                    b.iter(|| {
+                        let proto: MessageRound2Proto = bincode::deserialize(&frame).unwrap();
+                        convert::<_, MessageRound2>(&proto, params)
+                    })
+                },
+            );
+            group.finish();
+        }
+    }
+}
+
+criterion_group!(benches, bench_keygen, bench_rounds);
+criterion_main!(benches);
@@ -1,3 +1,18 @@
+//! Sizes of the messages of a session, and a report of the bytes every party sends and
+//! receives.
+
+use std::{collections::BTreeMap, fmt};
+
+use serde::Serialize;
+
+use crate::{
+    convert,
+    crs::run_coin_toss,
+    crs_commit, crs_reveal, gen_keys,
+    proto::{MessageRound1Proto, MessageRound2Proto, MessageRound3Proto},
+    round1, round2, MessageCrsCommit, MessageCrsReveal, PsiContext, PsiError, PsiMode,
+};
+
 pub trait BandwidthBench {
     fn get_byte_size(&self) -> usize;
 }
@@ -10,12 +25,12 @@ impl BandwidthBench for bfv::SecretKeyProto {
 
 impl BandwidthBench for bfv::CollectivePublicKeyShareProto {
     fn get_byte_size(&self) -> usize {
-        self.clone()
-            .share
-            .unwrap()
-            .coefficients
-            .iter()
-            .fold(0, |acc, vec| acc + vec.len())
+        self.share.as_ref().map_or(0, |share| {
+            share
+                .coefficients
+                .iter()
+                .fold(0, |acc, vec| acc + vec.len())
+        })
     }
 }
 
@@ -55,11 +70,234 @@ impl BandwidthBench for bfv::CollectiveRlkAggTrimmedShare1Proto {
 
 impl BandwidthBench for bfv::CollectiveDecryptionShareProto {
     fn get_byte_size(&self) -> usize {
-        self.clone()
-            .share
-            .unwrap()
-            .coefficients
+        self.share.as_ref().map_or(0, |share| {
+            share
+                .coefficients
+                .iter()
+                .fold(0, |acc, vec| acc + vec.len())
+        })
+    }
+}
+
+impl BandwidthBench for bfv::CollectiveRtgShareProto {
+    fn get_byte_size(&self) -> usize {
+        self.shares.iter().fold(0, |acc, poly| {
+            acc + poly.coefficients.iter().fold(0, |acc, vec| acc + vec.len())
+        })
+    }
+}
+
+impl BandwidthBench for MessageCrsCommit {
+    fn get_byte_size(&self) -> usize {
+        self.commitment.len()
+    }
+}
+
+impl BandwidthBench for MessageCrsReveal {
+    fn get_byte_size(&self) -> usize {
+        self.seed.len()
+    }
+}
+
+impl BandwidthBench for MessageRound1Proto {
+    fn get_byte_size(&self) -> usize {
+        self.share_rtgs.iter().fold(
+            self.share_pk.get_byte_size() + self.share_rlk1.get_byte_size(),
+            |acc, share| acc + share.get_byte_size(),
+        )
+    }
+}
+
+impl BandwidthBench for MessageRound2Proto {
+    fn get_byte_size(&self) -> usize {
+        self.cts
             .iter()
-            .fold(0, |acc, vec| acc + vec.len())
+            .fold(self.share_rlk2.get_byte_size(), |acc, ct| {
+                acc + ct.get_byte_size()
+            })
+    }
+}
+
+impl BandwidthBench for MessageRound3Proto {
+    fn get_byte_size(&self) -> usize {
+        self.decryption_shares
+            .iter()
+            .fold(0, |acc, share| acc + share.get_byte_size())
+    }
+}
+
+/// Bytes one party sends and receives for one message of the session.
+#[derive(Serialize, Clone, Debug, PartialEq, Eq)]
+pub struct MessageBandwidth {
+    pub message: String,
+    /// One copy of the party's message for every peer.
+    pub sent: usize,
+    /// One message from every peer.
+    pub received: usize,
+}
+
+/// Communication cost of a session for each of its parties, in the order the messages are
+/// exchanged.
+///
+/// Counts the bytes of the seeds, key shares and ciphertexts measured by [`BandwidthBench`],
+/// without the few bytes of parameters and the framing and encoding added on the wire.
+#[derive(Serialize, Clone, Debug, PartialEq, Eq)]
+pub struct BandwidthReport {
+    pub mode: PsiMode,
+    pub party_count: usize,
+    pub domain_size: usize,
+    pub messages: Vec<MessageBandwidth>,
+}
+
+impl BandwidthReport {
+    /// Measures the messages of a two party session on a bit vector of `domain_size` slots and
+    /// scales them to `party_count` parties. The size of every message only depends on the
+    /// parameters, the mode and the domain size, so all parties send messages of equal size.
+    pub fn generate(
+        psi_context: &PsiContext,
+        mode: PsiMode,
+        party_count: usize,
+        domain_size: usize,
+    ) -> Result<BandwidthReport, PsiError> {
+        let params = psi_context.bfv_parameters();
+        let bit_vector = vec![0; domain_size];
+
+        let (state, message_crs_commit) = crs_commit(0);
+        let (_, message_crs_reveal) =
+            crs_reveal(state, message_crs_commit.clone(), BTreeMap::new())?;
+
+        let crs = run_coin_toss(&[0, 1]);
+        let (a_psi_keys, a_message_round1) = gen_keys(psi_context, &crs, mode, 0);
+        let (b_psi_keys, b_message_round1) = gen_keys(psi_context, &crs, mode, 1);
+        let (a_state_round2, a_message_round2) = round1(
+            psi_context,
+            &a_psi_keys,
+            a_message_round1.clone(),
+            BTreeMap::from([(1, b_message_round1.clone())]),
+            &bit_vector,
+        )?;
+        let (_, b_message_round2) = round1(
+            psi_context,
+            &b_psi_keys,
+            b_message_round1,
+            BTreeMap::from([(0, a_message_round1.clone())]),
+            &bit_vector,
+        )?;
+        let (_, a_message_round3) = round2(
+            psi_context,
+            &a_psi_keys,
+            a_state_round2,
+            a_message_round2.clone(),
+            BTreeMap::from([(1, b_message_round2)]),
+        )?;
+
+        let sizes = [
+            ("MessageCrsCommit", message_crs_commit.get_byte_size()),
+            ("MessageCrsReveal", message_crs_reveal.get_byte_size()),
+            (
+                "MessageRound1",
+                convert::<_, MessageRound1Proto>(&a_message_round1, params).get_byte_size(),
+            ),
+            (
+                "MessageRound2",
+                convert::<_, MessageRound2Proto>(&a_message_round2, params).get_byte_size(),
+            ),
+            (
+                "MessageRound3",
+                convert::<_, MessageRound3Proto>(&a_message_round3, params).get_byte_size(),
+            ),
+        ];
+        let peer_count = party_count.saturating_sub(1);
+
+        Ok(BandwidthReport {
+            mode,
+            party_count,
+            domain_size,
+            messages: sizes
+                .into_iter()
+                .map(|(message, size)| MessageBandwidth {
+                    message: message.to_string(),
+                    sent: size * peer_count,
+                    received: size * peer_count,
+                })
+                .collect(),
+        })
+    }
+
+    pub fn total_sent(&self) -> usize {
+        self.messages.iter().map(|m| m.sent).sum()
+    }
+
+    pub fn total_received(&self) -> usize {
+        self.messages.iter().map(|m| m.received).sum()
+    }
+}
+
+impl fmt::Display for BandwidthReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "{:?} mode, {} parties, {} slots, bytes per party",
+            self.mode, self.party_count, self.domain_size
+        )?;
+        writeln!(f, "{:<18}{:>16}{:>16}", "message", "sent", "received")?;
+        for m in self.messages.iter() {
+            writeln!(f, "{:<18}{:>16}{:>16}", m.message, m.sent, m.received)?;
+        }
+        write!(
+            f,
+            "{:<18}{:>16}{:>16}",
+            "total",
+            self.total_sent(),
+            self.total_received()
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::PsiParameters;
+
+    #[test]
+    fn report_scales_with_parties_and_domain() {
+        let psi_context = PsiContext::new(PsiParameters::small());
+        let ring_size = psi_context.psi_parameters().ring_size();
+
+        let report =
+            BandwidthReport::generate(&psi_context, PsiMode::Intersection, 2, ring_size).unwrap();
+        let three_parties =
+            BandwidthReport::generate(&psi_context, PsiMode::Intersection, 3, ring_size).unwrap();
+        let three_chunks =
+            BandwidthReport::generate(&psi_context, PsiMode::Intersection, 2, 3 * ring_size)
+                .unwrap();
+
+        assert_eq!(report.messages.len(), 5);
+        assert_eq!(report.messages[0].sent, 32);
+        assert_eq!(three_parties.total_sent(), 2 * report.total_sent());
+        assert_eq!(three_parties.total_received(), 2 * report.total_received());
+        // the ciphertexts and decryption shares grow with the chunks, the key shares do not
+        assert_eq!(three_chunks.messages[2], report.messages[2]);
+        assert!(three_chunks.messages[3].sent > report.messages[3].sent);
+        assert_eq!(three_chunks.messages[4].sent, 3 * report.messages[4].sent);
+    }
+
+    #[test]
+    fn report_prints_table() {
+        let report = BandwidthReport {
+            mode: PsiMode::Cardinality,
+            party_count: 2,
+            domain_size: 10,
+            messages: vec![MessageBandwidth {
+                message: "MessageRound3".to_string(),
+                sent: 5,
+                received: 5,
+            }],
+        };
+
+        assert_eq!(
+            report.to_string().lines().last().unwrap(),
+            format!("{:<18}{:>16}{:>16}", "total", 5, 5)
+        );
     }
 }
@@ -0,0 +1,53 @@
+//! Relay that stores and forwards the messages of PSI sessions, see [`mp_psi::relay`].
+//!
+//! ```text
+//! mp-psi-relay [--listen <host:port>] [--directory <path>]
+//!              [--max-connections <n>] [--io-timeout <seconds>]
+//! ```
+
+use std::{net::TcpListener, path::PathBuf, time::Duration};
+
+use clap::Parser;
+use mp_psi::relay::{serve, RelayLimits};
+
+#[derive(Parser)]
+#[command(
+    name = "mp-psi-relay",
+    about = "Stores and forwards the messages of PSI sessions"
+)]
+struct Cli {
+    /// `host:port` to listen on.
+    #[arg(long, default_value = "127.0.0.1:8731")]
+    listen: String,
+    /// Directory to keep the messages in.
+    #[arg(long, default_value = "mailbox")]
+    directory: PathBuf,
+    /// Connections served at once.
+    #[arg(long, default_value_t = RelayLimits::default().max_connections)]
+    max_connections: usize,
+    /// Seconds a read from or write to a connection may block.
+    #[arg(long, default_value_t = RelayLimits::default().io_timeout.as_secs())]
+    io_timeout: u64,
+}
+
+fn main() {
+    let cli = Cli::parse();
+
+    let listener = TcpListener::bind(&cli.listen).unwrap_or_else(|e| {
+        eprintln!("error: cannot listen on {}: {e}", cli.listen);
+        std::process::exit(1);
+    });
+    eprintln!(
+        "relay listening on {}, storing messages in {}",
+        cli.listen,
+        cli.directory.display()
+    );
+    let limits = RelayLimits {
+        max_connections: cli.max_connections,
+        io_timeout: Duration::from_secs(cli.io_timeout),
+    };
+    if let Err(e) = serve(listener, cli.directory, limits, |e| eprintln!("relay: {e}")) {
+        eprintln!("error: {e}");
+        std::process::exit(1);
+    }
+}
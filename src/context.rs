@@ -0,0 +1,58 @@
+use bfv::{BfvParameters, Evaluator};
+
+use crate::PsiParameters;
+
+/// The parameters of a session together with the bfv parameters and evaluator derived from
+/// them.
+///
+/// Deriving the bfv parameters computes the moduli, NTT tables and key switching data, so a
+/// party builds the context once per session and passes it to every round.
+pub struct PsiContext {
+    psi_parameters: PsiParameters,
+    evaluator: Evaluator,
+}
+
+impl PsiContext {
+    pub fn new(psi_parameters: PsiParameters) -> PsiContext {
+        let evaluator = Evaluator::new(psi_parameters.bfv_parameters());
+        PsiContext {
+            psi_parameters,
+            evaluator,
+        }
+    }
+
+    pub fn psi_parameters(&self) -> &PsiParameters {
+        &self.psi_parameters
+    }
+
+    /// The bfv parameters, held by the evaluator.
+    pub fn bfv_parameters(&self) -> &BfvParameters {
+        self.evaluator.params()
+    }
+
+    pub fn evaluator(&self) -> &Evaluator {
+        &self.evaluator
+    }
+}
+
+impl From<PsiParameters> for PsiContext {
+    fn from(psi_parameters: PsiParameters) -> Self {
+        PsiContext::new(psi_parameters)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn context_keeps_one_copy_of_parameters() {
+        let psi_context = PsiContext::new(PsiParameters::small());
+
+        assert_eq!(psi_context.psi_parameters(), &PsiParameters::small());
+        assert!(std::ptr::eq(
+            psi_context.bfv_parameters(),
+            psi_context.evaluator().params()
+        ));
+    }
+}
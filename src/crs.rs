@@ -0,0 +1,295 @@
+//! Coin toss for the common reference string of a session.
+//!
+//! Every party commits to a random seed, then reveals it once it holds the commitments of all
+//! the other parties. The CRS seeds of the session are derived from all revealed seeds, so no
+//! party can bias them as long as one party is honest.
+//!
+//! The coin toss runs before [`gen_keys`](crate::gen_keys):
+//!
+//! 1. [`crs_commit`] returns the commitment sent to every peer.
+//! 2. [`crs_reveal`] takes the peers' commitments and returns the seed sent to every peer.
+//! 3. [`crs_derive`] takes the peers' seeds, checks them against their commitments and returns
+//!    the [`SessionCrs`].
+
+use std::collections::BTreeMap;
+
+use rand::{thread_rng, CryptoRng, RngCore};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::{with_own_message, PartyId, PsiError};
+
+const COMMITMENT_DOMAIN: &[u8] = b"mp-psi/crs/commitment";
+const SESSION_DOMAIN: &[u8] = b"mp-psi/crs/session";
+const CRS_PK_DOMAIN: &[u8] = b"mp-psi/crs/pk";
+const CRS_RLK_DOMAIN: &[u8] = b"mp-psi/crs/rlk";
+const CRS_RTG_DOMAIN: &[u8] = b"mp-psi/crs/rtg";
+
+/// Seeds of the common reference strings shared by all parties of a session.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct SessionCrs {
+    session_id: [u8; 32],
+    /// Parties that took part in the coin toss, in ascending order.
+    party_ids: Vec<PartyId>,
+    crs_pk: [u8; 32],
+    crs_rlk: [u8; 32],
+}
+
+impl SessionCrs {
+    /// Identifies the session. Equal for all parties that derived the same CRS.
+    pub fn session_id(&self) -> [u8; 32] {
+        self.session_id
+    }
+
+    /// Parties of the session, in ascending order. Every round expects a message from each
+    /// of them.
+    pub fn party_ids(&self) -> &[PartyId] {
+        &self.party_ids
+    }
+
+    pub(crate) fn crs_pk(&self) -> [u8; 32] {
+        self.crs_pk
+    }
+
+    pub(crate) fn crs_rlk(&self) -> [u8; 32] {
+        self.crs_rlk
+    }
+
+    /// Seed of the rotation key share for `rotate_by`. Every rotation needs its own seed.
+    pub(crate) fn crs_rtg(&self, rotate_by: isize) -> [u8; 32] {
+        Sha256::new_with_prefix(CRS_RTG_DOMAIN)
+            .chain_update(self.session_id)
+            .chain_update((rotate_by as i64).to_le_bytes())
+            .finalize()
+            .into()
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct StateCrsCommit {
+    party_id: PartyId,
+    seed: [u8; 32],
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct MessageCrsCommit {
+    pub(crate) commitment: [u8; 32],
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct StateCrsReveal {
+    party_id: PartyId,
+    seed: [u8; 32],
+    commitments: BTreeMap<PartyId, MessageCrsCommit>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct MessageCrsReveal {
+    pub(crate) seed: [u8; 32],
+}
+
+/// Samples the party's seed and commits to it.
+pub fn crs_commit(party_id: PartyId) -> (StateCrsCommit, MessageCrsCommit) {
+    crs_commit_with_rng(party_id, &mut thread_rng())
+}
+
+/// Like [`crs_commit`], sampling the seed from `rng`.
+pub fn crs_commit_with_rng<R: RngCore + CryptoRng>(
+    party_id: PartyId,
+    rng: &mut R,
+) -> (StateCrsCommit, MessageCrsCommit) {
+    let mut seed = [0u8; 32];
+    rng.fill_bytes(&mut seed);
+
+    let message = MessageCrsCommit {
+        commitment: commitment(party_id, &seed),
+    };
+
+    (StateCrsCommit { party_id, seed }, message)
+}
+
+/// Reveals the party's seed once the commitments of all peers are in.
+pub fn crs_reveal(
+    state: StateCrsCommit,
+    message: MessageCrsCommit,
+    other_messages: BTreeMap<PartyId, MessageCrsCommit>,
+) -> Result<(StateCrsReveal, MessageCrsReveal), PsiError> {
+    let commitments = with_own_message(state.party_id, message, other_messages)?;
+
+    Ok((
+        StateCrsReveal {
+            party_id: state.party_id,
+            seed: state.seed,
+            commitments,
+        },
+        MessageCrsReveal { seed: state.seed },
+    ))
+}
+
+/// Checks the peers' seeds against their commitments and derives the session CRS.
+pub fn crs_derive(
+    state: StateCrsReveal,
+    message: MessageCrsReveal,
+    other_messages: BTreeMap<PartyId, MessageCrsReveal>,
+) -> Result<SessionCrs, PsiError> {
+    let reveals = with_own_message(state.party_id, message, other_messages)?;
+
+    if let Some(party_id) = state
+        .commitments
+        .keys()
+        .find(|party_id| !reveals.contains_key(party_id))
+    {
+        return Err(PsiError::CoinToss {
+            party_id: *party_id,
+            reason: "seed was not revealed".to_string(),
+        });
+    }
+    for (party_id, reveal) in reveals.iter() {
+        let valid = state.commitments.get(party_id).map_or(false, |c| {
+            c.commitment == commitment(*party_id, &reveal.seed)
+        });
+        if !valid {
+            return Err(PsiError::CoinToss {
+                party_id: *party_id,
+                reason: "revealed seed does not match commitment".to_string(),
+            });
+        }
+    }
+
+    // Seeds are absorbed in ascending party order, so every party derives the same CRS.
+    let mut hasher = Sha256::new_with_prefix(SESSION_DOMAIN);
+    reveals.iter().for_each(|(party_id, reveal)| {
+        hasher.update(party_id.to_le_bytes());
+        hasher.update(reveal.seed);
+    });
+    let session_id: [u8; 32] = hasher.finalize().into();
+
+    Ok(SessionCrs {
+        session_id,
+        party_ids: reveals.keys().copied().collect(),
+        crs_pk: Sha256::new_with_prefix(CRS_PK_DOMAIN)
+            .chain_update(session_id)
+            .finalize()
+            .into(),
+        crs_rlk: Sha256::new_with_prefix(CRS_RLK_DOMAIN)
+            .chain_update(session_id)
+            .finalize()
+            .into(),
+    })
+}
+
+/// Binds the seed to the party, so a peer cannot replay someone else's commitment.
+fn commitment(party_id: PartyId, seed: &[u8; 32]) -> [u8; 32] {
+    Sha256::new_with_prefix(COMMITMENT_DOMAIN)
+        .chain_update(party_id.to_le_bytes())
+        .chain_update(seed)
+        .finalize()
+        .into()
+}
+
+/// Runs the coin toss between `party_ids` in this process and returns the CRS they agree on.
+pub(crate) fn run_coin_toss(party_ids: &[PartyId]) -> SessionCrs {
+    use itertools::Itertools;
+
+    fn others<T: Clone>(
+        party_id: PartyId,
+        messages: &BTreeMap<PartyId, T>,
+    ) -> BTreeMap<PartyId, T> {
+        messages
+            .iter()
+            .filter(|(id, _)| **id != party_id)
+            .map(|(id, m)| (*id, m.clone()))
+            .collect()
+    }
+
+    let (states, commitments): (Vec<_>, BTreeMap<_, _>) = party_ids
+        .iter()
+        .map(|party_id| {
+            let (state, message) = crs_commit(*party_id);
+            (state, (*party_id, message))
+        })
+        .unzip();
+    let (states, reveals): (Vec<_>, BTreeMap<_, _>) = states
+        .into_iter()
+        .map(|state| {
+            let party_id = state.party_id;
+            let (state, message) = crs_reveal(
+                state,
+                commitments[&party_id].clone(),
+                others(party_id, &commitments),
+            )
+            .unwrap();
+            (state, (party_id, message))
+        })
+        .unzip();
+    let crs = states
+        .into_iter()
+        .map(|state| {
+            let party_id = state.party_id;
+            crs_derive(
+                state,
+                reveals[&party_id].clone(),
+                others(party_id, &reveals),
+            )
+            .unwrap()
+        })
+        .collect_vec();
+
+    assert!(crs.iter().all_equal());
+    crs[0].clone()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn coin_toss_works() {
+        let crs_a = run_coin_toss(&[0, 1, 2]);
+        let crs_b = run_coin_toss(&[0, 1, 2]);
+
+        assert_ne!(crs_a, crs_b);
+        assert_ne!(crs_a.crs_pk(), crs_a.crs_rlk());
+        assert_ne!(crs_a.crs_rtg(1), crs_a.crs_rtg(2));
+        assert_eq!(crs_a.party_ids(), &[0, 1, 2]);
+    }
+
+    #[test]
+    fn crs_derive_rejects_changed_seed() {
+        let (a_state, a_commit) = crs_commit(0);
+        let (b_state, b_commit) = crs_commit(1);
+
+        let (a_state, a_reveal) = crs_reveal(
+            a_state,
+            a_commit.clone(),
+            BTreeMap::from([(1, b_commit.clone())]),
+        )
+        .unwrap();
+        let (_, mut b_reveal) =
+            crs_reveal(b_state, b_commit, BTreeMap::from([(0, a_commit)])).unwrap();
+        b_reveal.seed[0] ^= 1;
+
+        let result = crs_derive(a_state, a_reveal, BTreeMap::from([(1, b_reveal)]));
+
+        assert!(matches!(
+            result,
+            Err(PsiError::CoinToss { party_id: 1, .. })
+        ));
+    }
+
+    #[test]
+    fn crs_derive_rejects_missing_seed() {
+        let (a_state, a_commit) = crs_commit(0);
+        let (_, b_commit) = crs_commit(1);
+
+        let (a_state, a_reveal) =
+            crs_reveal(a_state, a_commit, BTreeMap::from([(1, b_commit)])).unwrap();
+
+        let result = crs_derive(a_state, a_reveal, BTreeMap::new());
+
+        assert!(matches!(
+            result,
+            Err(PsiError::CoinToss { party_id: 1, .. })
+        ));
+    }
+}
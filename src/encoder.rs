@@ -0,0 +1,145 @@
+use std::collections::BTreeMap;
+
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::{PsiError, SessionCrs};
+
+const KEY_DOMAIN: &[u8] = b"mp-psi/encoder/key";
+
+/// Hashes byte-string items into the bit vector domain of a session.
+///
+/// All parties must use the same domain size and key, otherwise equal items land in different
+/// slots. Distinct items that hash to the same slot are reported together, so the domain should
+/// be much larger than the square of the set sizes to keep false positives rare.
+#[derive(Clone, Debug)]
+pub struct ItemEncoder {
+    domain_size: usize,
+    key: [u8; 32],
+}
+
+/// Reverse lookup from the slots of the bit vector to the party's items.
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+pub struct ItemLookup {
+    items: BTreeMap<usize, Vec<Vec<u8>>>,
+}
+
+impl ItemEncoder {
+    pub fn new(domain_size: usize, key: [u8; 32]) -> Result<ItemEncoder, PsiError> {
+        if domain_size == 0 {
+            return Err(PsiError::InvalidParameters(
+                "domain size must not be zero".to_string(),
+            ));
+        }
+
+        Ok(ItemEncoder { domain_size, key })
+    }
+
+    /// Keys the encoder with the session id, so slots cannot be precomputed across sessions.
+    pub fn for_session(domain_size: usize, crs: &SessionCrs) -> Result<ItemEncoder, PsiError> {
+        let key = Sha256::new_with_prefix(KEY_DOMAIN)
+            .chain_update(crs.session_id())
+            .finalize()
+            .into();
+        ItemEncoder::new(domain_size, key)
+    }
+
+    pub fn domain_size(&self) -> usize {
+        self.domain_size
+    }
+
+    /// Slot of `item` in the bit vector.
+    pub fn index(&self, item: &[u8]) -> usize {
+        let digest = Sha256::new()
+            .chain_update(self.key)
+            .chain_update(item)
+            .finalize();
+        let value = u64::from_le_bytes(digest[..8].try_into().unwrap());
+        (value % self.domain_size as u64) as usize
+    }
+
+    /// Returns the bit vector to pass to `round1` and the lookup to decode the output of
+    /// `round3` with.
+    pub fn encode<I: AsRef<[u8]>>(&self, items: &[I]) -> (Vec<u32>, ItemLookup) {
+        let mut bit_vector = vec![0; self.domain_size];
+        let mut lookup = ItemLookup::default();
+
+        items.iter().for_each(|item| {
+            let index = self.index(item.as_ref());
+            bit_vector[index] = 1;
+            let slot = lookup.items.entry(index).or_default();
+            if !slot.iter().any(|i| i == item.as_ref()) {
+                slot.push(item.as_ref().to_vec());
+            }
+        });
+
+        (bit_vector, lookup)
+    }
+}
+
+impl ItemLookup {
+    /// Items the party encoded into slot `index`.
+    pub fn items_at(&self, index: usize) -> &[Vec<u8>] {
+        self.items.get(&index).map_or(&[], |items| items.as_slice())
+    }
+
+    /// Items of the party whose slot is set in `psi_output`.
+    pub fn intersection(&self, psi_output: &[u32]) -> Vec<Vec<u8>> {
+        self.items
+            .iter()
+            .filter(|(index, _)| psi_output.get(**index).map_or(false, |b| *b != 0))
+            .flat_map(|(_, items)| items.iter().cloned())
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use itertools::{izip, Itertools};
+
+    use super::*;
+
+    #[test]
+    fn encoding_works() {
+        let encoder = ItemEncoder::new(1 << 20, [7u8; 32]).unwrap();
+
+        let items_a = (0..20).map(|i| format!("item-{i}")).collect_vec();
+        let items_b = (10..30).map(|i| format!("item-{i}")).collect_vec();
+        let (bit_vector_a, lookup_a) = encoder.encode(&items_a);
+        let (bit_vector_b, lookup_b) = encoder.encode(&items_b);
+        assert_eq!(bit_vector_a.iter().sum::<u32>(), 20);
+
+        let psi_output = izip!(bit_vector_a.iter(), bit_vector_b.iter())
+            .map(|(a, b)| a * b)
+            .collect_vec();
+
+        let expected = (10..20)
+            .map(|i| format!("item-{i}").into_bytes())
+            .sorted()
+            .collect_vec();
+        assert_eq!(
+            lookup_a
+                .intersection(&psi_output)
+                .into_iter()
+                .sorted()
+                .collect_vec(),
+            expected
+        );
+        assert_eq!(
+            lookup_b
+                .intersection(&psi_output)
+                .into_iter()
+                .sorted()
+                .collect_vec(),
+            expected
+        );
+    }
+
+    #[test]
+    fn different_keys_use_different_slots() {
+        let encoder_a = ItemEncoder::new(1 << 20, [1u8; 32]).unwrap();
+        let encoder_b = ItemEncoder::new(1 << 20, [2u8; 32]).unwrap();
+
+        assert_ne!(encoder_a.index(b"alice"), encoder_b.index(b"alice"));
+    }
+}
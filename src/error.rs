@@ -0,0 +1,150 @@
+use std::fmt;
+
+use wasm_bindgen::{JsError, JsValue};
+
+use crate::PartyId;
+
+/// Errors returned by the protocol rounds.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PsiError {
+    /// A message or state could not be deserialized.
+    Deserialization(String),
+    /// An output could not be serialized.
+    Serialization(String),
+    /// A party sent a different number of ciphertexts than we encrypted.
+    CiphertextCountMismatch {
+        party_id: PartyId,
+        expected: usize,
+        found: usize,
+    },
+    /// A party sent a share that is missing, duplicated or of the wrong shape.
+    MalformedShare { party_id: PartyId, reason: String },
+    /// A message was produced under different BFV parameters than ours.
+    ParameterMismatch(String),
+    /// Custom parameters that cannot be used for the protocol.
+    InvalidParameters(String),
+    /// A party did not take part in the coin toss of the CRS as committed.
+    CoinToss { party_id: PartyId, reason: String },
+    /// The input contains a value at or above the input bound of the parameters.
+    InvalidInputBit {
+        index: usize,
+        value: u32,
+        bound: u32,
+    },
+    /// A party runs the session on a domain of a different size than ours.
+    DomainSizeMismatch {
+        party_id: PartyId,
+        expected: usize,
+        found: usize,
+    },
+    /// A party sent a key share that is a copy, negation or other multiple of the share of
+    /// `related_to`. Combinations of several shares are only ruled out by committing to the
+    /// shares with [`round1_commit`](crate::round1_commit).
+    RelatedShare {
+        party_id: PartyId,
+        related_to: PartyId,
+    },
+    /// A message names a different sender or session than the one it was received for. The
+    /// sender and session are not authenticated, so this catches misrouted messages only.
+    BindingMismatch { party_id: PartyId, reason: String },
+    /// A party committed to its key or decryption shares but did not reveal them.
+    MissingReveal { party_id: PartyId },
+    /// A party revealed other key or decryption shares than it committed to.
+    RevealMismatch { party_id: PartyId },
+    /// A party of the session sent no message for the round.
+    MissingMessage { party_id: PartyId },
+    /// A message comes from a party that did not take part in the coin toss of the session.
+    UnknownParty { party_id: PartyId },
+    /// A message could not be sent to or received from a party.
+    Transport { party_id: PartyId, reason: String },
+    /// A step of a [`PsiSession`](crate::PsiSession) was called out of order.
+    OutOfOrder { called: String, expected: String },
+}
+
+impl fmt::Display for PsiError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PsiError::Deserialization(reason) => write!(f, "failed to deserialize {reason}"),
+            PsiError::Serialization(reason) => write!(f, "failed to serialize {reason}"),
+            PsiError::CiphertextCountMismatch {
+                party_id,
+                expected,
+                found,
+            } => write!(
+                f,
+                "party {party_id} sent {found} ciphertexts, expected {expected}"
+            ),
+            PsiError::MalformedShare { party_id, reason } => {
+                write!(f, "malformed share from party {party_id}: {reason}")
+            }
+            PsiError::ParameterMismatch(reason) => write!(f, "parameter mismatch: {reason}"),
+            PsiError::InvalidParameters(reason) => write!(f, "invalid parameters: {reason}"),
+            PsiError::CoinToss { party_id, reason } => {
+                write!(f, "coin toss failed for party {party_id}: {reason}")
+            }
+            PsiError::InvalidInputBit {
+                index,
+                value,
+                bound,
+            } => write!(
+                f,
+                "bit vector holds {value} at index {index}, expected a value below {bound}"
+            ),
+            PsiError::DomainSizeMismatch {
+                party_id,
+                expected,
+                found,
+            } => write!(
+                f,
+                "party {party_id} runs on a domain of {found} slots, expected {expected}"
+            ),
+            PsiError::RelatedShare {
+                party_id,
+                related_to,
+            } => write!(
+                f,
+                "party {party_id} sent a key share related to the share of party {related_to}"
+            ),
+            PsiError::BindingMismatch { party_id, reason } => {
+                write!(
+                    f,
+                    "message from party {party_id} is not bound to it: {reason}"
+                )
+            }
+            PsiError::MissingReveal { party_id } => write!(
+                f,
+                "party {party_id} did not reveal the shares it committed to"
+            ),
+            PsiError::RevealMismatch { party_id } => write!(
+                f,
+                "party {party_id} revealed shares that do not match its commitment"
+            ),
+            PsiError::MissingMessage { party_id } => {
+                write!(f, "no message from party {party_id} of the session")
+            }
+            PsiError::UnknownParty { party_id } => {
+                write!(
+                    f,
+                    "message from party {party_id}, which is not in the session"
+                )
+            }
+            PsiError::Transport { party_id, reason } => {
+                write!(f, "transport to party {party_id} failed: {reason}")
+            }
+            PsiError::OutOfOrder { called, expected } => {
+                write!(
+                    f,
+                    "cannot run {called}, the session expects {expected} next"
+                )
+            }
+        }
+    }
+}
+
+impl std::error::Error for PsiError {}
+
+impl From<PsiError> for JsValue {
+    fn from(error: PsiError) -> Self {
+        JsError::new(&error.to_string()).into()
+    }
+}
@@ -0,0 +1,324 @@
+//! Commit-then-reveal of the decryption shares of round 3.
+//!
+//! With plain [`round3`](crate::round3), the first party to receive the decryption shares of all
+//! its peers can decrypt the output and withhold its own shares. In the fair mode every party
+//! first commits to its shares and only reveals them once it holds the commitments of all its
+//! peers. A party that stops after the commitments, or reveals other shares than it committed
+//! to, is named in the error of every other party:
+//!
+//! 1. [`round3_commit`] takes the message of [`round2`](crate::round2) and returns the
+//!    commitment sent to every peer.
+//! 2. [`round3_reveal`] takes the peers' commitments and returns the shares sent to every peer.
+//! 3. [`round3_open`] takes the peers' shares, checks them against their commitments and
+//!    returns the messages [`round3`](crate::round3) expects.
+//!
+//! The last party to reveal still sees the shares of all others first. The commitments do not
+//! stop it from aborting, but they make the abort attributable.
+
+use std::collections::BTreeMap;
+
+use rand::{thread_rng, CryptoRng, RngCore};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::{
+    convert, proto::MessageRound3Proto, with_own_message, MessageRound3, PartyId, PsiContext,
+    PsiError, PsiKeys,
+};
+
+const COMMITMENT_DOMAIN: &[u8] = b"mp-psi/round3/commitment";
+
+pub struct StateRound3Commit {
+    party_id: PartyId,
+    session_id: [u8; 32],
+    salt: [u8; 32],
+    message: MessageRound3,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct MessageRound3Commit {
+    pub(crate) commitment: [u8; 32],
+}
+
+pub struct StateRound3Reveal {
+    party_id: PartyId,
+    session_id: [u8; 32],
+    commitments: BTreeMap<PartyId, MessageRound3Commit>,
+}
+
+#[derive(Clone)]
+pub struct MessageRound3Reveal {
+    pub(crate) salt: [u8; 32],
+    pub(crate) message: MessageRound3,
+}
+
+/// Commits to the decryption shares of the party, bound to the session of `psi_keys`.
+pub fn round3_commit(
+    psi_context: &PsiContext,
+    psi_keys: &PsiKeys,
+    message: MessageRound3,
+) -> (StateRound3Commit, MessageRound3Commit) {
+    round3_commit_with_rng(psi_context, psi_keys, message, &mut thread_rng())
+}
+
+/// Like [`round3_commit`], sampling the salt of the commitment from `rng`.
+pub fn round3_commit_with_rng<R: RngCore + CryptoRng>(
+    psi_context: &PsiContext,
+    psi_keys: &PsiKeys,
+    message: MessageRound3,
+    rng: &mut R,
+) -> (StateRound3Commit, MessageRound3Commit) {
+    let party_id = psi_keys.party_id;
+    let session_id = psi_keys.crs.session_id();
+    let mut salt = [0u8; 32];
+    rng.fill_bytes(&mut salt);
+
+    let commitment = MessageRound3Commit {
+        commitment: commitment(psi_context, &session_id, party_id, &salt, &message),
+    };
+
+    (
+        StateRound3Commit {
+            party_id,
+            session_id,
+            salt,
+            message,
+        },
+        commitment,
+    )
+}
+
+/// Reveals the decryption shares of the party once the commitments of all peers are in.
+pub fn round3_reveal(
+    state: StateRound3Commit,
+    message: MessageRound3Commit,
+    other_messages: BTreeMap<PartyId, MessageRound3Commit>,
+) -> Result<(StateRound3Reveal, MessageRound3Reveal), PsiError> {
+    let commitments = with_own_message(state.party_id, message, other_messages)?;
+
+    Ok((
+        StateRound3Reveal {
+            party_id: state.party_id,
+            session_id: state.session_id,
+            commitments,
+        },
+        MessageRound3Reveal {
+            salt: state.salt,
+            message: state.message,
+        },
+    ))
+}
+
+/// Checks the peers' decryption shares against their commitments and returns the party's own
+/// round 3 message and those of its peers, ready for [`round3`](crate::round3).
+pub fn round3_open(
+    psi_context: &PsiContext,
+    state: StateRound3Reveal,
+    message: MessageRound3Reveal,
+    other_messages: BTreeMap<PartyId, MessageRound3Reveal>,
+) -> Result<(MessageRound3, BTreeMap<PartyId, MessageRound3>), PsiError> {
+    let mut reveals = with_own_message(state.party_id, message, other_messages)?;
+
+    if let Some(party_id) = state
+        .commitments
+        .keys()
+        .find(|party_id| !reveals.contains_key(party_id))
+    {
+        return Err(PsiError::MissingReveal {
+            party_id: *party_id,
+        });
+    }
+    for (party_id, reveal) in reveals.iter() {
+        let valid = state.commitments.get(party_id).map_or(false, |c| {
+            c.commitment
+                == commitment(
+                    psi_context,
+                    &state.session_id,
+                    *party_id,
+                    &reveal.salt,
+                    &reveal.message,
+                )
+        });
+        if !valid {
+            return Err(PsiError::RevealMismatch {
+                party_id: *party_id,
+            });
+        }
+    }
+
+    let own_message = reveals
+        .remove(&state.party_id)
+        .expect("own reveal was inserted")
+        .message;
+    let other_messages = reveals
+        .into_iter()
+        .map(|(party_id, reveal)| (party_id, reveal.message))
+        .collect();
+    Ok((own_message, other_messages))
+}
+
+/// Binds the shares to the session and the party, so a commitment cannot be replayed in
+/// another session. The salt hides shares that carry little noise.
+fn commitment(
+    psi_context: &PsiContext,
+    session_id: &[u8; 32],
+    party_id: PartyId,
+    salt: &[u8; 32],
+    message: &MessageRound3,
+) -> [u8; 32] {
+    let shares = bincode::serialize(&convert::<_, MessageRound3Proto>(
+        message,
+        psi_context.bfv_parameters(),
+    ))
+    .expect("decryption shares serialize");
+
+    Sha256::new_with_prefix(COMMITMENT_DOMAIN)
+        .chain_update(session_id)
+        .chain_update(party_id.to_le_bytes())
+        .chain_update(salt)
+        .chain_update(shares)
+        .finalize()
+        .into()
+}
+
+#[cfg(test)]
+mod tests {
+    use itertools::Itertools;
+
+    use super::*;
+    use crate::{
+        round3,
+        tests::{others, run_rounds, run_rounds_with_keys},
+        PsiMode, PsiParameters, StateRound3,
+    };
+
+    /// Runs three parties up to their decryption shares, then commits to and reveals them. Three
+    /// parties need the depth of the medium preset.
+    fn run_commitments(
+        psi_context: &PsiContext,
+    ) -> (
+        Vec<StateRound3>,
+        Vec<StateRound3Reveal>,
+        BTreeMap<PartyId, MessageRound3Reveal>,
+    ) {
+        let bit_vectors = vec![vec![1, 1, 0, 1], vec![1, 0, 1, 1], vec![1, 1, 1, 1]];
+        let (keys, states_round3, messages_round3) =
+            run_rounds_with_keys(psi_context, PsiMode::Intersection, &bit_vectors);
+
+        let (states_commit, commitments): (Vec<_>, BTreeMap<_, _>) = keys
+            .iter()
+            .zip(messages_round3.into_values())
+            .map(|(psi_keys, message)| {
+                let (state, commitment) = round3_commit(psi_context, psi_keys, message);
+                (state, (psi_keys.party_id, commitment))
+            })
+            .unzip();
+        let (states_reveal, reveals): (Vec<_>, BTreeMap<_, _>) = states_commit
+            .into_iter()
+            .map(|state| {
+                let party_id = state.party_id;
+                let (state, reveal) = round3_reveal(
+                    state,
+                    commitments[&party_id].clone(),
+                    others(&commitments, party_id),
+                )
+                .unwrap();
+                (state, (party_id, reveal))
+            })
+            .unzip();
+
+        (
+            states_round3
+                .into_iter()
+                .map(|(_, state)| state)
+                .collect_vec(),
+            states_reveal,
+            reveals,
+        )
+    }
+
+    /// Opens the reveals for every party and decrypts its output.
+    fn open_and_decrypt<'a>(
+        psi_context: &'a PsiContext,
+        states_round3: Vec<StateRound3>,
+        states_reveal: Vec<StateRound3Reveal>,
+        reveals: &'a BTreeMap<PartyId, MessageRound3Reveal>,
+    ) -> impl Iterator<Item = Vec<u32>> + 'a {
+        states_round3
+            .into_iter()
+            .zip(states_reveal)
+            .map(move |(state_round3, state_reveal)| {
+                let party_id = state_reveal.party_id;
+                let (message, other_messages) = round3_open(
+                    psi_context,
+                    state_reveal,
+                    reveals[&party_id].clone(),
+                    others(reveals, party_id),
+                )
+                .unwrap();
+                round3(psi_context, state_round3, message, other_messages).unwrap()
+            })
+    }
+
+    #[test]
+    fn fair_round3_works() {
+        let psi_context = PsiContext::new(PsiParameters::medium());
+        let (states_round3, states_reveal, reveals) = run_commitments(&psi_context);
+
+        open_and_decrypt(&psi_context, states_round3, states_reveal, &reveals)
+            .for_each(|psi_output| assert_eq!(psi_output, vec![1, 0, 0, 1]));
+    }
+
+    #[test]
+    fn round3_open_detects_abort_after_commitments() {
+        let psi_context = PsiContext::new(PsiParameters::medium());
+        let (_, mut states_reveal, mut reveals) = run_commitments(&psi_context);
+
+        // party 2 holds every commitment, then keeps its shares to itself
+        reveals.remove(&2);
+        let state = states_reveal.remove(0);
+        let result = round3_open(
+            &psi_context,
+            state,
+            reveals[&0].clone(),
+            others(&reveals, 0),
+        );
+
+        assert_eq!(result.err(), Some(PsiError::MissingReveal { party_id: 2 }));
+    }
+
+    #[test]
+    fn round3_open_detects_changed_shares() {
+        let psi_context = PsiContext::new(PsiParameters::medium());
+        let (_, mut states_reveal, mut reveals) = run_commitments(&psi_context);
+
+        // party 1 reveals the shares of party 2 in place of its own
+        let replaced = reveals[&2].message.clone();
+        reveals.get_mut(&1).unwrap().message = replaced;
+        let state = states_reveal.remove(0);
+        let result = round3_open(
+            &psi_context,
+            state,
+            reveals[&0].clone(),
+            others(&reveals, 0),
+        );
+
+        assert_eq!(result.err(), Some(PsiError::RevealMismatch { party_id: 1 }));
+    }
+
+    #[test]
+    fn commitment_binds_session() {
+        let psi_context = PsiContext::new(PsiParameters::small());
+        let (_, messages_round3) = run_rounds(
+            &psi_context,
+            PsiMode::Intersection,
+            &[vec![1, 0], vec![1, 1]],
+        );
+        let salt = [7; 32];
+
+        assert_ne!(
+            commitment(&psi_context, &[0; 32], 0, &salt, &messages_round3[&0]),
+            commitment(&psi_context, &[1; 32], 0, &salt, &messages_round3[&0])
+        );
+    }
+}
@@ -0,0 +1,296 @@
+//! Commit-then-reveal of the key shares of round 1.
+//!
+//! The collective keys are sums of one share per party. A peer that sees the shares of the others
+//! before sending its own can choose it as a combination of theirs, such as its own share minus
+//! the share of a victim, which cancels the victim out of the key. The check of
+//! [`convert_peer_messages`](crate::convert_peer_messages) only catches a share that is a multiple
+//! of a single other share. Here every party first commits to the message of
+//! [`gen_keys`](crate::gen_keys) and only reveals it once it holds the commitments of all its
+//! peers, so no share can depend on the shares of others:
+//!
+//! 1. [`round1_commit`] takes the message of [`gen_keys`](crate::gen_keys) and returns the
+//!    commitment sent to every peer.
+//! 2. [`round1_reveal`] takes the peers' commitments and returns the shares sent to every peer.
+//! 3. [`round1_open`] takes the peers' shares, checks them against their commitments and
+//!    returns the messages [`round1`](crate::round1) expects.
+//!
+//! A commitment is bound to the session and to its sender, so a peer cannot copy the commitment
+//! of another party and later reveal that party's shares as its own.
+
+use std::collections::BTreeMap;
+
+use rand::{thread_rng, CryptoRng, RngCore};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::{
+    convert, proto::MessageRound1Proto, with_own_message, MessageRound1, PartyId, PsiContext,
+    PsiError, PsiKeys,
+};
+
+const COMMITMENT_DOMAIN: &[u8] = b"mp-psi/round1/commitment";
+
+pub struct StateRound1Commit {
+    party_id: PartyId,
+    session_id: [u8; 32],
+    salt: [u8; 32],
+    message: MessageRound1,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct MessageRound1Commit {
+    pub(crate) commitment: [u8; 32],
+}
+
+pub struct StateRound1Reveal {
+    party_id: PartyId,
+    session_id: [u8; 32],
+    commitments: BTreeMap<PartyId, MessageRound1Commit>,
+}
+
+#[derive(Clone)]
+pub struct MessageRound1Reveal {
+    pub(crate) salt: [u8; 32],
+    pub(crate) message: MessageRound1,
+}
+
+/// Commits to the key shares of the party, bound to the session of `psi_keys`.
+pub fn round1_commit(
+    psi_context: &PsiContext,
+    psi_keys: &PsiKeys,
+    message: MessageRound1,
+) -> (StateRound1Commit, MessageRound1Commit) {
+    round1_commit_with_rng(psi_context, psi_keys, message, &mut thread_rng())
+}
+
+/// Like [`round1_commit`], sampling the salt of the commitment from `rng`.
+pub fn round1_commit_with_rng<R: RngCore + CryptoRng>(
+    psi_context: &PsiContext,
+    psi_keys: &PsiKeys,
+    message: MessageRound1,
+    rng: &mut R,
+) -> (StateRound1Commit, MessageRound1Commit) {
+    let party_id = psi_keys.party_id;
+    let session_id = psi_keys.crs.session_id();
+    let mut salt = [0u8; 32];
+    rng.fill_bytes(&mut salt);
+
+    let commitment = MessageRound1Commit {
+        commitment: commitment(psi_context, &session_id, party_id, &salt, &message),
+    };
+
+    (
+        StateRound1Commit {
+            party_id,
+            session_id,
+            salt,
+            message,
+        },
+        commitment,
+    )
+}
+
+/// Reveals the key shares of the party once the commitments of all peers are in.
+pub fn round1_reveal(
+    state: StateRound1Commit,
+    message: MessageRound1Commit,
+    other_messages: BTreeMap<PartyId, MessageRound1Commit>,
+) -> Result<(StateRound1Reveal, MessageRound1Reveal), PsiError> {
+    let commitments = with_own_message(state.party_id, message, other_messages)?;
+
+    Ok((
+        StateRound1Reveal {
+            party_id: state.party_id,
+            session_id: state.session_id,
+            commitments,
+        },
+        MessageRound1Reveal {
+            salt: state.salt,
+            message: state.message,
+        },
+    ))
+}
+
+/// Checks the peers' key shares against their commitments and returns the party's own round 1
+/// message and those of its peers, ready for [`round1`](crate::round1).
+pub fn round1_open(
+    psi_context: &PsiContext,
+    state: StateRound1Reveal,
+    message: MessageRound1Reveal,
+    other_messages: BTreeMap<PartyId, MessageRound1Reveal>,
+) -> Result<(MessageRound1, BTreeMap<PartyId, MessageRound1>), PsiError> {
+    let mut reveals = with_own_message(state.party_id, message, other_messages)?;
+
+    if let Some(party_id) = state
+        .commitments
+        .keys()
+        .find(|party_id| !reveals.contains_key(party_id))
+    {
+        return Err(PsiError::MissingReveal {
+            party_id: *party_id,
+        });
+    }
+    for (party_id, reveal) in reveals.iter() {
+        let valid = state.commitments.get(party_id).map_or(false, |c| {
+            c.commitment
+                == commitment(
+                    psi_context,
+                    &state.session_id,
+                    *party_id,
+                    &reveal.salt,
+                    &reveal.message,
+                )
+        });
+        if !valid {
+            return Err(PsiError::RevealMismatch {
+                party_id: *party_id,
+            });
+        }
+    }
+
+    let own_message = reveals
+        .remove(&state.party_id)
+        .expect("own reveal was inserted")
+        .message;
+    let other_messages = reveals
+        .into_iter()
+        .map(|(party_id, reveal)| (party_id, reveal.message))
+        .collect();
+    Ok((own_message, other_messages))
+}
+
+/// Binds the shares to the session and the party. The salt keeps the shares hidden until they
+/// are revealed.
+fn commitment(
+    psi_context: &PsiContext,
+    session_id: &[u8; 32],
+    party_id: PartyId,
+    salt: &[u8; 32],
+    message: &MessageRound1,
+) -> [u8; 32] {
+    let shares = bincode::serialize(&convert::<_, MessageRound1Proto>(
+        message,
+        psi_context.bfv_parameters(),
+    ))
+    .expect("key shares serialize");
+
+    Sha256::new_with_prefix(COMMITMENT_DOMAIN)
+        .chain_update(session_id)
+        .chain_update(party_id.to_le_bytes())
+        .chain_update(salt)
+        .chain_update(shares)
+        .finalize()
+        .into()
+}
+
+#[cfg(test)]
+mod tests {
+    use itertools::Itertools;
+
+    use super::*;
+    use crate::{crs::run_coin_toss, gen_keys, round1, tests::others, PsiMode, PsiParameters};
+
+    /// Generates the keys of three parties, then commits to and reveals their key shares.
+    fn run_commitments(
+        psi_context: &PsiContext,
+    ) -> (
+        Vec<PsiKeys>,
+        Vec<StateRound1Reveal>,
+        BTreeMap<PartyId, MessageRound1Reveal>,
+    ) {
+        let crs = run_coin_toss(&[0, 1, 2]);
+        let (keys, states_commit, commitments): (Vec<_>, Vec<_>, BTreeMap<_, _>) = (0..3)
+            .map(|party_id| {
+                let (psi_keys, message) =
+                    gen_keys(psi_context, &crs, PsiMode::Intersection, party_id);
+                let (state, commitment) = round1_commit(psi_context, &psi_keys, message);
+                (psi_keys, state, (party_id, commitment))
+            })
+            .multiunzip();
+        let (states_reveal, reveals): (Vec<_>, BTreeMap<_, _>) = states_commit
+            .into_iter()
+            .map(|state| {
+                let party_id = state.party_id;
+                let (state, reveal) = round1_reveal(
+                    state,
+                    commitments[&party_id].clone(),
+                    others(&commitments, party_id),
+                )
+                .unwrap();
+                (state, (party_id, reveal))
+            })
+            .unzip();
+
+        (keys, states_reveal, reveals)
+    }
+
+    #[test]
+    fn committed_round1_works() {
+        // three parties need the depth of the medium preset
+        let psi_context = PsiContext::new(PsiParameters::medium());
+        let (keys, states_reveal, reveals) = run_commitments(&psi_context);
+
+        keys.iter()
+            .zip(states_reveal)
+            .for_each(|(psi_keys, state)| {
+                let (message, other_messages) = round1_open(
+                    &psi_context,
+                    state,
+                    reveals[&psi_keys.party_id].clone(),
+                    others(&reveals, psi_keys.party_id),
+                )
+                .unwrap();
+                assert!(round1(&psi_context, psi_keys, message, other_messages, &[1, 0]).is_ok());
+            });
+    }
+
+    #[test]
+    fn round1_open_detects_missing_reveal() {
+        let psi_context = PsiContext::new(PsiParameters::small());
+        let (_, mut states_reveal, mut reveals) = run_commitments(&psi_context);
+
+        reveals.remove(&2);
+        let state = states_reveal.remove(0);
+        let result = round1_open(
+            &psi_context,
+            state,
+            reveals[&0].clone(),
+            others(&reveals, 0),
+        );
+
+        assert_eq!(result.err(), Some(PsiError::MissingReveal { party_id: 2 }));
+    }
+
+    #[test]
+    fn round1_open_detects_changed_shares() {
+        let psi_context = PsiContext::new(PsiParameters::small());
+        let (_, mut states_reveal, mut reveals) = run_commitments(&psi_context);
+
+        // party 2 waits for the shares of party 0 and reveals a share that depends on them
+        let share_pk = reveals[&0].message.share_pk.clone();
+        reveals.get_mut(&2).unwrap().message.share_pk = share_pk;
+        let state = states_reveal.remove(1);
+        let result = round1_open(
+            &psi_context,
+            state,
+            reveals[&1].clone(),
+            others(&reveals, 1),
+        );
+
+        assert_eq!(result.err(), Some(PsiError::RevealMismatch { party_id: 2 }));
+    }
+
+    #[test]
+    fn commitment_binds_party() {
+        let psi_context = PsiContext::new(PsiParameters::small());
+        let (psi_keys, message) =
+            gen_keys(&psi_context, &run_coin_toss(&[0]), PsiMode::Intersection, 0);
+        let session_id = psi_keys.crs.session_id();
+        let salt = [7; 32];
+
+        assert_ne!(
+            commitment(&psi_context, &session_id, 0, &salt, &message),
+            commitment(&psi_context, &session_id, 1, &salt, &message)
+        );
+    }
+}
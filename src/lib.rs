@@ -1,204 +1,801 @@
-use std::char::ParseCharError;
+use std::collections::BTreeMap;
 
 use bfv::{
-    BfvParameters, Ciphertext, CiphertextProto, CollectiveDecryption, CollectiveDecryptionShare,
-    CollectiveDecryptionShareProto, CollectivePublicKeyGenerator, CollectivePublicKeyShare,
-    CollectivePublicKeyShareProto, CollectiveRlkAggShare1, CollectiveRlkAggTrimmedShare1,
-    CollectiveRlkAggTrimmedShare1Proto, CollectiveRlkGenerator, CollectiveRlkShare1,
-    CollectiveRlkShare1Proto, CollectiveRlkShare2, CollectiveRlkShare2Proto, Encoding,
-    EvaluationKey, Evaluator, Plaintext, SecretKey, SecretKeyProto,
+    BfvParameters, Ciphertext, CollectiveDecryption, CollectiveDecryptionShare,
+    CollectivePublicKeyGenerator, CollectivePublicKeyShare, CollectiveRlkAggTrimmedShare1,
+    CollectiveRlkGenerator, CollectiveRlkShare1, CollectiveRlkShare2, CollectiveRtgGenerator,
+    CollectiveRtgShare, Encoding, EvaluationKey, Evaluator, GaloisKey, Plaintext, SecretKey,
+    SecretKeyProto,
 };
 use itertools::{izip, Itertools};
-use rand::thread_rng;
-use serde::{de, Deserialize, Serialize};
-use traits::{
-    TryDecodingWithParameters, TryEncodingWithParameters, TryFromWithLevelledParameters,
-    TryFromWithParameters,
-};
-use wasm_bindgen::{prelude::wasm_bindgen, JsValue};
+use rand::{thread_rng, CryptoRng, RngCore, SeedableRng};
+use rand_chacha::ChaCha20Rng;
+use traits::{TryDecodingWithParameters, TryEncodingWithParameters, TryFromWithParameters};
+use zeroize::Zeroize;
 
 mod bandwidth_benches;
+mod context;
+mod crs;
+mod encoder;
+mod error;
+mod fairness;
+mod key_commitment;
+mod mode;
+mod parameters;
+pub mod proto;
+pub mod relay;
+mod session;
+#[cfg(test)]
+mod test_vectors;
+mod transport;
+mod validation;
+mod wasm;
 
-static CRS_PK: [u8; 32] = [13u8; 32];
-static CRS_RLK: [u8; 32] = [121u8; 32];
+pub use bandwidth_benches::{BandwidthBench, BandwidthReport, MessageBandwidth};
+pub use context::PsiContext;
+pub use crs::{
+    crs_commit, crs_commit_with_rng, crs_derive, crs_reveal, MessageCrsCommit, MessageCrsReveal,
+    SessionCrs, StateCrsCommit, StateCrsReveal,
+};
+pub use encoder::{ItemEncoder, ItemLookup};
+pub use error::PsiError;
+pub use fairness::{
+    round3_commit, round3_commit_with_rng, round3_open, round3_reveal, MessageRound3Commit,
+    MessageRound3Reveal, StateRound3Commit, StateRound3Reveal,
+};
+pub use key_commitment::{
+    round1_commit, round1_commit_with_rng, round1_open, round1_reveal, MessageRound1Commit,
+    MessageRound1Reveal, StateRound1Commit, StateRound1Reveal,
+};
+pub use mode::{PsiMode, PsiOutput};
+pub use parameters::PsiParameters;
+pub use session::{run_fair_session, run_session};
+pub use transport::{ChannelTransport, DuplexStream, StreamTransport, Transport};
+pub use validation::{convert_peer_messages, SharePolys};
+pub use wasm::PsiSession;
 
-static RING_SIZE: usize = 1 << 11;
+pub type PartyId = u32;
 
-fn params() -> BfvParameters {
-    let mut params = BfvParameters::new_with_primes(
-        vec![1032193, 1073692673],
-        vec![995329, 1073668097],
-        40961,
-        RING_SIZE,
-    );
-    params.enable_hybrid_key_switching_with_prime(vec![61441]);
-    params.enable_pke();
-    params
+/// Secret keys of a party, kept between the rounds and never sent.
+///
+/// The keys are only held in their serialized form, which is overwritten with zeros when
+/// `PsiKeys` is dropped or [destroyed](PsiKeys::destroy). bfv's `SecretKey` does not expose its
+/// coefficients and cannot be wiped, so `round1` and `round2` build it from the serialized form
+/// and drop it again as soon as they have used it.
+pub struct PsiKeys {
+    party_id: PartyId,
+    crs: SessionCrs,
+    mode: PsiMode,
+    s: SecretKeyProto,
+    s_rlk: SecretKeyProto,
 }
 
-struct PsiKeys {
-    s: SecretKey,
-    s_rlk: SecretKey,
+impl PsiKeys {
+    /// Wipes the keys. A party no longer needs them once `round2` returned its decryption
+    /// shares.
+    pub fn destroy(mut self) {
+        self.wipe();
+    }
+
+    fn wipe(&mut self) {
+        wipe_secret_key(&mut self.s);
+        wipe_secret_key(&mut self.s_rlk);
+    }
+
+    fn secret_key(&self, params: &BfvParameters) -> SecretKey {
+        convert(&self.s, params)
+    }
+
+    fn secret_key_rlk(&self, params: &BfvParameters) -> SecretKey {
+        convert(&self.s_rlk, params)
+    }
+}
+
+impl Drop for PsiKeys {
+    fn drop(&mut self) {
+        self.wipe();
+    }
+}
+
+/// Overwrites the coefficients of a serialized secret key with zeros and empties them.
+pub(crate) fn wipe_secret_key(s: &mut SecretKeyProto) {
+    s.coefficients.zeroize();
 }
 
 #[derive(Clone)]
-struct MessageRound1 {
+pub struct MessageRound1 {
+    /// Sender of the message and session it belongs to, which the receivers check against the
+    /// peer they got it from and their own session. Both are declared by the sender and not
+    /// authenticated, so the check only catches messages delivered to the wrong party or
+    /// session by mistake, not a peer that lies about them.
+    party_id: PartyId,
+    session_id: [u8; 32],
+    parameters: PsiParameters,
+    mode: PsiMode,
     share_pk: CollectivePublicKeyShare,
     share_rlk1: CollectiveRlkShare1,
+    /// One share per rotation of [`PsiMode::rotations`], empty in intersection mode.
+    share_rtgs: Vec<CollectiveRtgShare>,
 }
 
-fn gen_keys() -> (PsiKeys, MessageRound1) {
-    let params = params();
-    let mut rng = thread_rng();
-    let s = SecretKey::random_with_params(&params, &mut rng);
-    let s_rlk = CollectiveRlkGenerator::init_state(&params, &mut rng);
+/// Generates the keys of the party and the shares of the collective keys sent to all peers.
+pub fn gen_keys(
+    psi_context: &PsiContext,
+    crs: &SessionCrs,
+    mode: PsiMode,
+    party_id: PartyId,
+) -> (PsiKeys, MessageRound1) {
+    gen_keys_with_rng(psi_context, crs, mode, party_id, &mut thread_rng())
+}
 
-    let share_pk = CollectivePublicKeyGenerator::generate_share(&params, &s, CRS_PK, &mut rng);
+/// Like [`gen_keys`], sampling all randomness from `rng`.
+pub fn gen_keys_with_rng<R: RngCore + CryptoRng>(
+    psi_context: &PsiContext,
+    crs: &SessionCrs,
+    mode: PsiMode,
+    party_id: PartyId,
+    rng: &mut R,
+) -> (PsiKeys, MessageRound1) {
+    let psi_parameters = psi_context.psi_parameters();
+    let params = psi_context.bfv_parameters();
+    let s = SecretKey::random_with_params(params, rng);
+    let s_rlk = CollectiveRlkGenerator::init_state(params, rng);
+
+    let share_pk = CollectivePublicKeyGenerator::generate_share(params, &s, crs.crs_pk(), rng);
     let share_rlk1 =
-        CollectiveRlkGenerator::generate_share_1(&params, &s, &s_rlk, CRS_RLK, 0, &mut rng);
+        CollectiveRlkGenerator::generate_share_1(params, &s, &s_rlk, crs.crs_rlk(), 0, rng);
+    let share_rtgs = mode
+        .rotations(psi_parameters.ring_size())
+        .into_iter()
+        .map(|rotate_by| {
+            CollectiveRtgGenerator::generate_share(
+                params,
+                &s,
+                rotate_by,
+                crs.crs_rtg(rotate_by),
+                0,
+                rng,
+            )
+        })
+        .collect_vec();
 
     (
-        PsiKeys { s, s_rlk },
+        PsiKeys {
+            party_id,
+            crs: crs.clone(),
+            mode,
+            s: convert(&s, params),
+            s_rlk: convert(&s_rlk, params),
+        },
         MessageRound1 {
+            party_id,
+            session_id: crs.session_id(),
+            parameters: psi_parameters.clone(),
+            mode,
             share_pk,
             share_rlk1,
+            share_rtgs,
         },
     )
 }
 
-struct StateRound2 {
+pub struct StateRound2 {
+    party_id: PartyId,
+    /// Parties of the session, see [`SessionCrs::party_ids`].
+    party_ids: Vec<PartyId>,
     rlk_agg1_trimmed: CollectiveRlkAggTrimmedShare1,
+    /// Rotation keys of [`PsiMode::rotations`], empty in intersection mode.
+    rtgs: Vec<GaloisKey>,
 }
 
 #[derive(Clone)]
-struct MessageRound2 {
+pub struct MessageRound2 {
+    /// Length of the party's bit vector, which all parties must agree on.
+    domain_size: usize,
     share_rlk2: CollectiveRlkShare2,
     cts: Vec<Ciphertext>,
 }
 
-fn round1(
+/// Checks the party's own input to [`round1`]: it must not be empty and every value must lie
+/// below the input bound of the parameters.
+pub(crate) fn check_input(
+    psi_parameters: &PsiParameters,
+    bit_vector: &[u32],
+) -> Result<(), PsiError> {
+    if bit_vector.is_empty() {
+        return Err(PsiError::InvalidParameters(
+            "bit vector must not be empty".to_string(),
+        ));
+    }
+    let bound = psi_parameters.input_bound();
+    if bound < 2 || bound as u64 > psi_parameters.plaintext_modulus() {
+        return Err(PsiError::InvalidParameters(format!(
+            "input bound {bound} is not between 2 and the plaintext modulus {}",
+            psi_parameters.plaintext_modulus()
+        )));
+    }
+    if let Some((index, value)) = bit_vector.iter().find_position(|b| **b >= bound) {
+        return Err(PsiError::InvalidInputBit {
+            index,
+            value: *value,
+            bound,
+        });
+    }
+    Ok(())
+}
+
+/// Aggregates the collective keys and encrypts the party's bit vector.
+///
+/// The shares of `other_messages` are not checked, convert the peers' messages with
+/// [`convert_peer_messages`] before passing them in. Exchange the messages of [`gen_keys`] with
+/// [`round1_commit`] so that no peer can choose its shares after seeing ours.
+pub fn round1(
+    psi_context: &PsiContext,
     psi_keys: &PsiKeys,
     message: MessageRound1,
-    other_message: MessageRound1,
+    other_messages: BTreeMap<PartyId, MessageRound1>,
     bit_vector: &[u32],
-) -> (StateRound2, MessageRound2) {
-    let params = params();
-    let mut rng = thread_rng();
+) -> Result<(StateRound2, MessageRound2), PsiError> {
+    round1_with_rng(
+        psi_context,
+        psi_keys,
+        message,
+        other_messages,
+        bit_vector,
+        &mut thread_rng(),
+    )
+}
+
+/// Like [`round1`], sampling all randomness from `rng`.
+pub fn round1_with_rng<R: RngCore + CryptoRng>(
+    psi_context: &PsiContext,
+    psi_keys: &PsiKeys,
+    message: MessageRound1,
+    other_messages: BTreeMap<PartyId, MessageRound1>,
+    bit_vector: &[u32],
+    rng: &mut R,
+) -> Result<(StateRound2, MessageRound2), PsiError> {
+    let psi_parameters = psi_context.psi_parameters();
+    let params = psi_context.bfv_parameters();
+
+    check_input(psi_parameters, bit_vector)?;
+
+    if let Some((party_id, m)) = other_messages
+        .iter()
+        .find(|(party_id, m)| m.party_id != **party_id)
+    {
+        return Err(PsiError::BindingMismatch {
+            party_id: *party_id,
+            reason: format!("message names party {} as its sender", m.party_id),
+        });
+    }
+    if let Some(party_id) = other_messages
+        .iter()
+        .find(|(_, m)| m.session_id != psi_keys.crs.session_id())
+        .map(|(party_id, _)| *party_id)
+    {
+        return Err(PsiError::BindingMismatch {
+            party_id,
+            reason: "message belongs to a different session".to_string(),
+        });
+    }
+    check_parties(psi_keys.party_id, psi_keys.crs.party_ids(), &other_messages)?;
+    if let Some(party_id) = other_messages
+        .iter()
+        .find(|(_, m)| &m.parameters != psi_parameters)
+        .map(|(party_id, _)| *party_id)
+    {
+        return Err(PsiError::ParameterMismatch(format!(
+            "party {party_id} uses different parameters"
+        )));
+    }
+    if let Some((party_id, m)) = other_messages.iter().find(|(_, m)| m.mode != psi_keys.mode) {
+        return Err(PsiError::ParameterMismatch(format!(
+            "party {party_id} runs {:?} mode, expected {:?}",
+            m.mode, psi_keys.mode
+        )));
+    }
+
+    // The count is computed modulo the plaintext modulus, so the domain must stay below it.
+    if psi_keys.mode.counts() && bit_vector.len() as u64 >= psi_parameters.plaintext_modulus() {
+        return Err(PsiError::InvalidParameters(format!(
+            "{:?} mode needs a domain of 1 to {} slots, found {}",
+            psi_keys.mode,
+            psi_parameters.plaintext_modulus() - 1,
+            bit_vector.len()
+        )));
+    }
+    if psi_keys.mode == (PsiMode::Threshold { k: 0 }) {
+        return Err(PsiError::InvalidParameters(
+            "threshold must be at least 1".to_string(),
+        ));
+    }
+    let party_count = other_messages.len() + 1;
+    let depth = psi_keys.mode.depth(party_count);
+    let max_depth = psi_parameters.multiplicative_depth();
+    if depth > max_depth {
+        return Err(PsiError::InvalidParameters(format!(
+            "{party_count} parties in {:?} mode need depth {depth}, parameters hold {max_depth}",
+            psi_keys.mode
+        )));
+    }
+    // the flooding noise of every party's decryption share adds up
+    let flooding_bits = psi_parameters.smudging_bits() as usize + mode::ceil_log2(party_count) + 1;
+    let noise_budget = psi_parameters.noise_budget(depth);
+    if psi_parameters.smudging_bits() > 0 && flooding_bits > noise_budget {
+        return Err(PsiError::InvalidParameters(format!(
+            "flooding noise of {flooding_bits} bits exceeds the noise budget of {noise_budget} bits"
+        )));
+    }
+
+    let rotations = psi_keys.mode.rotations(psi_parameters.ring_size());
+    if let Some((party_id, m)) = other_messages
+        .iter()
+        .find(|(_, m)| m.share_rtgs.len() != rotations.len())
+    {
+        return Err(PsiError::MalformedShare {
+            party_id: *party_id,
+            reason: format!(
+                "expected {} rotation key shares, found {}",
+                rotations.len(),
+                m.share_rtgs.len()
+            ),
+        });
+    }
+
+    let messages = with_own_message(psi_keys.party_id, message, other_messages)?;
+    let (shares_pk, shares_rlk1, shares_rtgs): (Vec<_>, Vec<_>, Vec<_>) = messages
+        .into_values()
+        .map(|m| (m.share_pk, m.share_rlk1, m.share_rtgs))
+        .multiunzip();
 
     // generate pk
     let collective_pk = CollectivePublicKeyGenerator::aggregate_shares_and_finalise(
-        &params,
-        &vec![message.share_pk, other_message.share_pk],
-        CRS_PK,
+        params,
+        &shares_pk,
+        psi_keys.crs.crs_pk(),
     );
 
     // generate rlk share 2
-    let rlk_agg1 = CollectiveRlkGenerator::aggregate_shares_1(
-        &params,
-        &vec![message.share_rlk1, other_message.share_rlk1],
-        0,
-    );
+    let rlk_agg1 = CollectiveRlkGenerator::aggregate_shares_1(params, &shares_rlk1, 0);
     let share_rlk2 = CollectiveRlkGenerator::generate_share_2(
-        &params,
-        &psi_keys.s,
+        params,
+        &psi_keys.secret_key(params),
         &rlk_agg1,
-        &psi_keys.s_rlk,
+        &psi_keys.secret_key_rlk(params),
         0,
-        &mut rng,
+        rng,
     );
 
-    // encrypt bit vector
-    let ciphertexts = bit_vector
-        .chunks(RING_SIZE)
-        .map(|v| {
-            let pt = Plaintext::try_encoding_with_parameters(v, &params, Encoding::default());
-            collective_pk.encrypt(&params, &pt, &mut rng)
+    // generate rotation keys
+    let rtgs = rotations
+        .iter()
+        .enumerate()
+        .map(|(i, rotate_by)| {
+            let shares = shares_rtgs.iter().map(|s| s[i].clone()).collect_vec();
+            CollectiveRtgGenerator::aggregate_shares_and_finalise(
+                params,
+                &shares,
+                *rotate_by,
+                psi_keys.crs.crs_rtg(*rotate_by),
+                0,
+            )
         })
         .collect_vec();
 
-    (
+    // encrypt bit vector
+    let chunks = bit_vector.chunks(psi_parameters.ring_size()).collect_vec();
+    let ciphertexts = map_chunks_with_rng(&chunks, rng, |rng, v| {
+        let pt = Plaintext::try_encoding_with_parameters(*v, params, Encoding::default());
+        collective_pk.encrypt(params, &pt, rng)
+    });
+
+    Ok((
         StateRound2 {
+            party_id: psi_keys.party_id,
+            party_ids: psi_keys.crs.party_ids().to_vec(),
             rlk_agg1_trimmed: rlk_agg1.trim(),
+            rtgs,
         },
         MessageRound2 {
+            domain_size: bit_vector.len(),
             share_rlk2,
             cts: ciphertexts,
         },
-    )
+    ))
 }
 
 #[derive(Clone)]
-struct MessageRound3 {
+pub struct MessageRound3 {
     decryption_shares: Vec<CollectiveDecryptionShare>,
 }
 
-struct StateRound3 {
+pub struct StateRound3 {
+    party_id: PartyId,
+    /// Parties of the session, see [`SessionCrs::party_ids`].
+    party_ids: Vec<PartyId>,
+    mode: PsiMode,
+    /// Length of the bit vectors, which the decrypted chunks are cut down to.
+    domain_size: usize,
     cts_res: Vec<Ciphertext>,
 }
 
-fn round2(
+/// Multiplies the bit vectors of all parties and returns the party's decryption shares.
+///
+/// Like [`round1`], expects the peers' messages to come from [`convert_peer_messages`].
+pub fn round2(
+    psi_context: &PsiContext,
+    psi_keys: &PsiKeys,
+    state_round2: StateRound2,
+    message: MessageRound2,
+    other_messages: BTreeMap<PartyId, MessageRound2>,
+) -> Result<(StateRound3, MessageRound3), PsiError> {
+    round2_with_rng(
+        psi_context,
+        psi_keys,
+        state_round2,
+        message,
+        other_messages,
+        &mut thread_rng(),
+    )
+}
+
+/// Like [`round2`], sampling all randomness from `rng`.
+pub fn round2_with_rng<R: RngCore + CryptoRng>(
+    psi_context: &PsiContext,
     psi_keys: &PsiKeys,
     state_round2: StateRound2,
     message: MessageRound2,
-    other_message: MessageRound2,
-    is_a: bool,
-) -> (StateRound3, MessageRound3) {
-    let params = params();
-    let mut rng = thread_rng();
+    other_messages: BTreeMap<PartyId, MessageRound2>,
+    rng: &mut R,
+) -> Result<(StateRound3, MessageRound3), PsiError> {
+    let psi_parameters = psi_context.psi_parameters();
+    let params = psi_context.bfv_parameters();
+
+    check_parties(
+        state_round2.party_id,
+        &state_round2.party_ids,
+        &other_messages,
+    )?;
+    let chunk_count = message.cts.len();
+    if let Some((party_id, m)) = other_messages
+        .iter()
+        .find(|(_, m)| m.cts.len() != chunk_count)
+    {
+        return Err(PsiError::CiphertextCountMismatch {
+            party_id: *party_id,
+            expected: chunk_count,
+            found: m.cts.len(),
+        });
+    }
+    // Domains of the same number of chunks still differ in the padding of the last chunk,
+    // which would silently join the intersection.
+    let domain_size = message.domain_size;
+    if let Some((party_id, m)) = other_messages
+        .iter()
+        .find(|(_, m)| m.domain_size != domain_size)
+    {
+        return Err(PsiError::DomainSizeMismatch {
+            party_id: *party_id,
+            expected: domain_size,
+            found: m.domain_size,
+        });
+    }
+    let messages = with_own_message(psi_keys.party_id, message, other_messages)?;
 
     // Create RLK
+    let shares_rlk2 = messages
+        .values()
+        .map(|m| m.share_rlk2.clone())
+        .collect_vec();
     let rlk = CollectiveRlkGenerator::aggregate_shares_2(
-        &params,
-        &vec![message.share_rlk2, other_message.share_rlk2],
+        params,
+        &shares_rlk2,
         state_round2.rlk_agg1_trimmed,
         0,
     );
 
     // perform PSI
-    let evaluator = Evaluator::new(params.clone());
-    let evaluation_key = EvaluationKey::new_raw(&[0], vec![rlk], &[], &[], vec![]);
-    let cts_res = izip!(message.cts.iter(), other_message.cts.iter())
-        .map(|(ca, cb)| {
-            let ct_out = {
-                if is_a {
-                    evaluator.mul(ca, cb)
-                } else {
-                    evaluator.mul(cb, ca)
-                }
-            };
-            evaluator.relinearize(&ct_out, &evaluation_key)
-        })
-        .collect_vec();
-    let decryption_shares = cts_res
+    //
+    // Every party multiplies the ciphertexts in ascending party order so that all of them end
+    // up with the same `cts_res`, which the decryption shares of round 3 depend on.
+    let evaluator = psi_context.evaluator();
+    let rotations = psi_keys.mode.rotations(psi_parameters.ring_size());
+    let evaluation_key = EvaluationKey::new_raw(
+        &[0],
+        vec![rlk],
+        &vec![0; rotations.len()],
+        &rotations,
+        state_round2.rtgs,
+    );
+    let cts_res = map_chunks(&(0..chunk_count).collect_vec(), |chunk| {
+        let cts = messages
+            .values()
+            .map(|m| m.cts[*chunk].clone())
+            .collect_vec();
+        product_tree(evaluator, &evaluation_key, cts)
+    });
+    let ring_size = psi_parameters.ring_size();
+    let cts_res = match psi_keys.mode {
+        PsiMode::Intersection => cts_res,
+        PsiMode::Cardinality => vec![mode::intersection_size(
+            evaluator,
+            &evaluation_key,
+            &cts_res,
+            ring_size,
+        )],
+        PsiMode::Threshold { k } => {
+            // below the threshold the mask is zero and so is every decrypted slot
+            let size = mode::intersection_size(evaluator, &evaluation_key, &cts_res, ring_size);
+            let mask = mode::threshold_mask(evaluator, &evaluation_key, &size, k, ring_size);
+            map_chunks(&cts_res, |c| {
+                let ct_out = evaluator.mul(c, &mask);
+                evaluator.relinearize(&ct_out, &evaluation_key)
+            })
+        }
+    };
+    let s = &psi_keys.secret_key(params);
+    let decryption_shares = map_chunks_with_rng(&cts_res, rng, |rng, c| {
+        match psi_parameters.smudging_bits() {
+            0 => CollectiveDecryption::generate_share(evaluator.params(), c, s, rng),
+            smudging_bits => CollectiveDecryption::generate_share_with_smudging(
+                evaluator.params(),
+                c,
+                s,
+                smudging_bits as usize,
+                rng,
+            ),
+        }
+    });
+
+    Ok((
+        StateRound3 {
+            party_id: state_round2.party_id,
+            party_ids: state_round2.party_ids,
+            mode: psi_keys.mode,
+            domain_size,
+            cts_res,
+        },
+        MessageRound3 { decryption_shares },
+    ))
+}
+
+/// Decrypts the output of the session from the decryption shares of all parties, one value per
+/// slot of the domain. In [`PsiMode::Cardinality`] the output is a single value, the number of
+/// slots in the intersection.
+///
+/// Like [`round1`], expects the peers' messages to come from [`convert_peer_messages`].
+pub fn round3(
+    psi_context: &PsiContext,
+    state_round3: StateRound3,
+    message: MessageRound3,
+    other_messages: BTreeMap<PartyId, MessageRound3>,
+) -> Result<Vec<u32>, PsiError> {
+    let params = psi_context.bfv_parameters();
+
+    check_parties(
+        state_round3.party_id,
+        &state_round3.party_ids,
+        &other_messages,
+    )?;
+    let chunk_count = state_round3.cts_res.len();
+    if let Some((party_id, m)) = other_messages
         .iter()
-        .map(|c| {
-            CollectiveDecryption::generate_share(evaluator.params(), &c, &psi_keys.s, &mut rng)
-        })
-        .collect_vec();
+        .find(|(_, m)| m.decryption_shares.len() != chunk_count)
+    {
+        return Err(PsiError::MalformedShare {
+            party_id: *party_id,
+            reason: format!(
+                "expected {chunk_count} decryption shares, found {}",
+                m.decryption_shares.len()
+            ),
+        });
+    }
 
-    (StateRound3 { cts_res }, MessageRound3 { decryption_shares })
+    let mut shares_per_chunk = (0..chunk_count).map(|_| vec![]).collect_vec();
+    std::iter::once(message)
+        .chain(other_messages.into_values())
+        .for_each(|m| {
+            izip!(&mut shares_per_chunk, m.decryption_shares)
+                .for_each(|(shares, share)| shares.push(share));
+        });
+
+    let chunks = izip!(&state_round3.cts_res, &shares_per_chunk).collect_vec();
+    let psi_output = map_chunks(&chunks, |(c, shares)| {
+        let pt = CollectiveDecryption::aggregate_share_and_decrypt(params, c, shares);
+        Vec::<u32>::try_decoding_with_parameters(&pt, params, Encoding::default())
+    })
+    .concat();
+
+    let mut psi_output = match state_round3.mode {
+        PsiMode::Intersection => psi_output,
+        // above the threshold the intersecting slots hold the value of the mask
+        PsiMode::Threshold { .. } => psi_output
+            .into_iter()
+            .map(|b| (b != 0) as u32)
+            .collect_vec(),
+        // every slot holds the total
+        PsiMode::Cardinality => psi_output[..1].to_vec(),
+    };
+    // the last chunk is padded with zeros
+    psi_output.truncate(state_round3.domain_size);
+
+    Ok(psi_output)
 }
 
-fn round3(
+/// Like [`round3`], returning the indices of the slots in the intersection in ascending order.
+pub fn round3_indices(
+    psi_context: &PsiContext,
     state_round3: StateRound3,
     message: MessageRound3,
-    other_message: MessageRound3,
-) -> Vec<u32> {
-    let params = params();
-    izip!(
-        state_round3.cts_res.iter(),
-        message.decryption_shares.into_iter(),
-        other_message.decryption_shares.into_iter()
-    )
-    .flat_map(|(c, share_a, share_b)| {
-        let pt =
-            CollectiveDecryption::aggregate_share_and_decrypt(&params, c, &vec![share_a, share_b]);
-        Vec::<u32>::try_decoding_with_parameters(&pt, &params, Encoding::default())
+    other_messages: BTreeMap<PartyId, MessageRound3>,
+) -> Result<Vec<usize>, PsiError> {
+    if state_round3.mode == PsiMode::Cardinality {
+        return Err(PsiError::ParameterMismatch(
+            "session runs Cardinality mode, which reveals no indices".to_string(),
+        ));
+    }
+
+    let psi_output = round3(psi_context, state_round3, message, other_messages)?;
+    Ok(psi_output.into_iter().positions(|b| b != 0).collect_vec())
+}
+
+/// Like [`round3`] for sessions in [`PsiMode::Cardinality`], returning the number of slots in
+/// the intersection.
+pub fn round3_cardinality(
+    psi_context: &PsiContext,
+    state_round3: StateRound3,
+    message: MessageRound3,
+    other_messages: BTreeMap<PartyId, MessageRound3>,
+) -> Result<u32, PsiError> {
+    if state_round3.mode != PsiMode::Cardinality {
+        return Err(PsiError::ParameterMismatch(format!(
+            "session runs {:?} mode, expected Cardinality",
+            state_round3.mode
+        )));
+    }
+
+    let psi_output = round3(psi_context, state_round3, message, other_messages)?;
+    Ok(psi_output[0])
+}
+
+/// Checks that `other_messages` holds a message from every other party of the session and
+/// from no one else, so that no round aggregates the shares of only some of the parties.
+fn check_parties<T>(
+    party_id: PartyId,
+    party_ids: &[PartyId],
+    other_messages: &BTreeMap<PartyId, T>,
+) -> Result<(), PsiError> {
+    if other_messages.contains_key(&party_id) {
+        return Err(PsiError::MalformedShare {
+            party_id,
+            reason: "peer message carries our own party id".to_string(),
+        });
+    }
+    if let Some(party_id) = std::iter::once(&party_id)
+        .chain(other_messages.keys())
+        .find(|party_id| !party_ids.contains(party_id))
+    {
+        return Err(PsiError::UnknownParty {
+            party_id: *party_id,
+        });
+    }
+    if let Some(party_id) = party_ids
+        .iter()
+        .find(|id| **id != party_id && !other_messages.contains_key(id))
+    {
+        return Err(PsiError::MissingMessage {
+            party_id: *party_id,
+        });
+    }
+    Ok(())
+}
+
+/// Inserts the party's own message next to the ones received from its peers.
+fn with_own_message<T>(
+    party_id: PartyId,
+    message: T,
+    mut other_messages: BTreeMap<PartyId, T>,
+) -> Result<BTreeMap<PartyId, T>, PsiError> {
+    if other_messages.contains_key(&party_id) {
+        return Err(PsiError::MalformedShare {
+            party_id,
+            reason: "peer message carries our own party id".to_string(),
+        });
+    }
+    other_messages.insert(party_id, message);
+    Ok(other_messages)
+}
+
+/// Multiplies `cts` together in a balanced binary tree, relinearizing after every
+/// multiplication. The tree has depth `ceil(log2(cts.len()))`.
+fn product_tree(
+    evaluator: &Evaluator,
+    evaluation_key: &EvaluationKey,
+    cts: Vec<Ciphertext>,
+) -> Ciphertext {
+    let mut layer = cts;
+    while layer.len() > 1 {
+        layer = layer
+            .chunks(2)
+            .map(|pair| match pair {
+                [ca, cb] => {
+                    let ct_out = evaluator.mul(ca, cb);
+                    evaluator.relinearize(&ct_out, evaluation_key)
+                }
+                [c] => c.clone(),
+                _ => unreachable!(),
+            })
+            .collect_vec();
+    }
+    layer.pop().expect("product of zero ciphertexts")
+}
+
+/// `Sync` with the `parallel` feature, where chunks are shared with the rayon thread pool, and
+/// implemented by every type without it.
+#[cfg(feature = "parallel")]
+trait MaybeSync: Sync {}
+#[cfg(feature = "parallel")]
+impl<T: Sync + ?Sized> MaybeSync for T {}
+#[cfg(not(feature = "parallel"))]
+trait MaybeSync {}
+#[cfg(not(feature = "parallel"))]
+impl<T: ?Sized> MaybeSync for T {}
+
+/// `Send` with the `parallel` feature, and implemented by every type without it.
+#[cfg(feature = "parallel")]
+trait MaybeSend: Send {}
+#[cfg(feature = "parallel")]
+impl<T: Send + ?Sized> MaybeSend for T {}
+#[cfg(not(feature = "parallel"))]
+trait MaybeSend {}
+#[cfg(not(feature = "parallel"))]
+impl<T: ?Sized> MaybeSend for T {}
+
+/// Maps `f` over the chunks of a session, keeping their order. With the `parallel` feature the
+/// chunks are spread over the rayon thread pool.
+fn map_chunks<T, U, F>(chunks: &[T], f: F) -> Vec<U>
+where
+    T: MaybeSync,
+    U: MaybeSend,
+    F: Fn(&T) -> U + MaybeSync + MaybeSend,
+{
+    #[cfg(feature = "parallel")]
+    {
+        use rayon::prelude::*;
+        chunks.par_iter().map(f).collect()
+    }
+    #[cfg(not(feature = "parallel"))]
+    {
+        chunks.iter().map(f).collect()
+    }
+}
+
+/// Like [`map_chunks`] for an `f` that samples randomness. Every chunk gets its own RNG, seeded
+/// from `rng` in chunk order, so the output only depends on `rng`, with or without the
+/// `parallel` feature.
+fn map_chunks_with_rng<T, U, F, R>(chunks: &[T], rng: &mut R, f: F) -> Vec<U>
+where
+    T: MaybeSync,
+    U: MaybeSend,
+    F: Fn(&mut ChaCha20Rng, &T) -> U + MaybeSync + MaybeSend,
+    R: RngCore + CryptoRng,
+{
+    let seeded_chunks = chunks
+        .iter()
+        .map(|chunk| {
+            let mut seed = <ChaCha20Rng as SeedableRng>::Seed::default();
+            rng.fill_bytes(&mut seed);
+            (chunk, seed)
+        })
+        .collect_vec();
+    map_chunks(&seeded_chunks, |(chunk, seed)| {
+        f(&mut ChaCha20Rng::from_seed(*seed), chunk)
     })
-    .collect_vec()
 }
 
-fn convert<T, U>(value: &T, parameters: &BfvParameters) -> U
+/// Converts between the protocol types and their serializable twins in [`proto`].
+pub fn convert<T, U>(value: &T, parameters: &BfvParameters) -> U
 where
     U: TryFromWithParameters<Value = T, Parameters = BfvParameters>,
 {
@@ -209,6 +806,7 @@ where
 mod tests {
     use super::*;
     use crate::bandwidth_benches::BandwidthBench;
+    use crate::crs::run_coin_toss;
     use itertools::{izip, Itertools};
     use rand::{distributions::Uniform, Rng};
 
@@ -224,64 +822,791 @@ mod tests {
         bit_vector
     }
 
-    fn plain_psi(bit_vector0: &[u32], bit_vector_1: &[u32]) -> Vec<u32> {
-        izip!(bit_vector0.iter(), bit_vector_1.iter())
-            .map(|(b0, b1)| b0 * b1)
-            .collect_vec()
+    fn plain_psi(bit_vectors: &[Vec<u32>]) -> Vec<u32> {
+        bit_vectors
+            .iter()
+            .map(|v| v.to_vec())
+            .reduce(|acc, v| {
+                izip!(acc.iter(), v.iter())
+                    .map(|(b0, b1)| b0 * b1)
+                    .collect_vec()
+            })
+            .unwrap()
+    }
+
+    /// Returns the messages of every party except `party_id`.
+    pub(crate) fn others<T: Clone>(
+        messages: &BTreeMap<PartyId, T>,
+        party_id: PartyId,
+    ) -> BTreeMap<PartyId, T> {
+        messages
+            .iter()
+            .filter(|(id, _)| **id != party_id)
+            .map(|(id, m)| (*id, m.clone()))
+            .collect()
+    }
+
+    /// Runs the coin toss, `gen_keys`, `round1` and `round2` for parties `0..bit_vectors.len()`.
+    /// Returns the round 3 state of every party and the round 3 messages of all parties.
+    pub(crate) fn run_rounds(
+        psi_context: &PsiContext,
+        mode: PsiMode,
+        bit_vectors: &[Vec<u32>],
+    ) -> (
+        Vec<(PartyId, StateRound3)>,
+        BTreeMap<PartyId, MessageRound3>,
+    ) {
+        let (_, states, messages) = run_rounds_with_keys(psi_context, mode, bit_vectors);
+        (states, messages)
+    }
+
+    /// Like [`run_rounds`], also returning the keys of every party.
+    pub(crate) fn run_rounds_with_keys(
+        psi_context: &PsiContext,
+        mode: PsiMode,
+        bit_vectors: &[Vec<u32>],
+    ) -> (
+        Vec<PsiKeys>,
+        Vec<(PartyId, StateRound3)>,
+        BTreeMap<PartyId, MessageRound3>,
+    ) {
+        let party_ids = (0..bit_vectors.len() as PartyId).collect_vec();
+        let crs = run_coin_toss(&party_ids);
+
+        let (keys, messages_round1): (Vec<_>, BTreeMap<_, _>) = party_ids
+            .iter()
+            .map(|party_id| {
+                let (psi_keys, message) = gen_keys(psi_context, &crs, mode, *party_id);
+                (psi_keys, (*party_id, message))
+            })
+            .unzip();
+
+        let (states_round2, messages_round2): (Vec<_>, BTreeMap<_, _>) = izip!(&keys, bit_vectors)
+            .map(|(psi_keys, bit_vector)| {
+                let (state, message) = round1(
+                    psi_context,
+                    psi_keys,
+                    messages_round1[&psi_keys.party_id].clone(),
+                    others(&messages_round1, psi_keys.party_id),
+                    bit_vector,
+                )
+                .unwrap();
+                (state, (psi_keys.party_id, message))
+            })
+            .unzip();
+
+        let (states_round3, messages_round3) = izip!(&keys, states_round2)
+            .map(|(psi_keys, state)| {
+                let (state, message) = round2(
+                    psi_context,
+                    psi_keys,
+                    state,
+                    messages_round2[&psi_keys.party_id].clone(),
+                    others(&messages_round2, psi_keys.party_id),
+                )
+                .unwrap();
+                ((psi_keys.party_id, state), (psi_keys.party_id, message))
+            })
+            .unzip();
+        (keys, states_round3, messages_round3)
     }
 
     #[test]
     fn psi_works() {
+        let psi_parameters = PsiParameters::small();
+        let psi_context = PsiContext::new(psi_parameters.clone());
+        let crs = run_coin_toss(&[0, 1]);
         let hamming_weight = 1000;
-        let vector_size = RING_SIZE * 3;
+        let vector_size = psi_parameters.ring_size() * 3;
 
         // gen keys
-        let (a_psi_keys, a_message_round1) = gen_keys();
-        let (b_psi_keys, b_message_round1) = gen_keys();
+        let (a_psi_keys, a_message_round1) = gen_keys(&psi_context, &crs, PsiMode::Intersection, 0);
+        let (b_psi_keys, b_message_round1) = gen_keys(&psi_context, &crs, PsiMode::Intersection, 1);
 
         // round1
         let a_bit_vector = random_bit_vector(hamming_weight, vector_size);
         let b_bit_vector = random_bit_vector(hamming_weight, vector_size);
         let (a_state_round2, a_message_round2) = round1(
+            &psi_context,
             &a_psi_keys,
             a_message_round1.clone(),
-            b_message_round1.clone(),
+            BTreeMap::from([(1, b_message_round1.clone())]),
             &a_bit_vector,
-        );
+        )
+        .unwrap();
         let (b_state_round2, b_message_round2) = round1(
+            &psi_context,
             &b_psi_keys,
             b_message_round1,
-            a_message_round1,
+            BTreeMap::from([(0, a_message_round1)]),
             &b_bit_vector,
-        );
+        )
+        .unwrap();
 
         // round2
         let (a_state_round3, a_message_round3) = round2(
+            &psi_context,
             &a_psi_keys,
             a_state_round2,
             a_message_round2.clone(),
-            b_message_round2.clone(),
-            true,
-        );
+            BTreeMap::from([(1, b_message_round2.clone())]),
+        )
+        .unwrap();
         let (b_state_round3, b_message_round3) = round2(
+            &psi_context,
             &b_psi_keys,
             b_state_round2,
             b_message_round2,
-            a_message_round2,
-            false,
-        );
+            BTreeMap::from([(0, a_message_round2)]),
+        )
+        .unwrap();
 
         // round3
         let a_psi_output = round3(
+            &psi_context,
             a_state_round3,
             a_message_round3.clone(),
-            b_message_round3.clone(),
-        );
-        let b_psi_output = round3(b_state_round3, b_message_round3, a_message_round3);
+            BTreeMap::from([(1, b_message_round3.clone())]),
+        )
+        .unwrap();
+        let b_psi_output = round3(
+            &psi_context,
+            b_state_round3,
+            b_message_round3,
+            BTreeMap::from([(0, a_message_round3)]),
+        )
+        .unwrap();
 
-        let expected_psi_output = plain_psi(&a_bit_vector, &b_bit_vector);
+        let expected_psi_output = plain_psi(&[a_bit_vector, b_bit_vector]);
 
-        assert_eq!(expected_psi_output, a_psi_output[..vector_size]);
+        assert_eq!(expected_psi_output, a_psi_output);
         assert_eq!(a_psi_output, b_psi_output);
     }
+
+    #[test]
+    fn round3_returns_exact_length() {
+        let psi_parameters = PsiParameters::small();
+        let psi_context = PsiContext::new(psi_parameters.clone());
+        // the second chunk holds only a few slots
+        let vector_size = psi_parameters.ring_size() + 10;
+        let bit_vectors = [vec![1; vector_size], random_bit_vector(500, vector_size)];
+
+        let (states_round3, messages_round3) =
+            run_rounds(&psi_context, PsiMode::Intersection, &bit_vectors);
+        let expected_psi_output = plain_psi(&bit_vectors);
+        let mut states_round3 = states_round3.into_iter();
+
+        let (party_id, state) = states_round3.next().unwrap();
+        let psi_output = round3(
+            &psi_context,
+            state,
+            messages_round3[&party_id].clone(),
+            others(&messages_round3, party_id),
+        )
+        .unwrap();
+        assert_eq!(psi_output, expected_psi_output);
+
+        let (party_id, state) = states_round3.next().unwrap();
+        let indices = round3_indices(
+            &psi_context,
+            state,
+            messages_round3[&party_id].clone(),
+            others(&messages_round3, party_id),
+        )
+        .unwrap();
+        assert_eq!(
+            indices,
+            expected_psi_output
+                .iter()
+                .positions(|b| *b != 0)
+                .collect_vec()
+        );
+    }
+
+    #[test]
+    fn multi_party_psi_works() {
+        // three parties need a product tree of depth two, which the small preset cannot hold
+        let psi_parameters = PsiParameters::medium();
+        let psi_context = PsiContext::new(psi_parameters.clone());
+        let party_count = 3;
+        let crs = run_coin_toss(&(0..party_count).collect_vec());
+        let hamming_weight = 1500;
+        let vector_size = psi_parameters.ring_size() * 2;
+
+        let bit_vectors = (0..party_count)
+            .map(|_| random_bit_vector(hamming_weight, vector_size))
+            .collect_vec();
+
+        // gen keys
+        let (keys, messages_round1): (Vec<_>, BTreeMap<_, _>) = (0..party_count)
+            .map(|party_id| {
+                let (psi_keys, message) =
+                    gen_keys(&psi_context, &crs, PsiMode::Intersection, party_id);
+                (psi_keys, (party_id, message))
+            })
+            .unzip();
+
+        // round1
+        let (states_round2, messages_round2): (Vec<_>, BTreeMap<_, _>) = izip!(&keys, &bit_vectors)
+            .map(|(psi_keys, bit_vector)| {
+                let (state, message) = round1(
+                    &psi_context,
+                    psi_keys,
+                    messages_round1[&psi_keys.party_id].clone(),
+                    others(&messages_round1, psi_keys.party_id),
+                    bit_vector,
+                )
+                .unwrap();
+                (state, (psi_keys.party_id, message))
+            })
+            .unzip();
+
+        // round2
+        let (states_round3, messages_round3): (Vec<_>, BTreeMap<_, _>) =
+            izip!(&keys, states_round2)
+                .map(|(psi_keys, state)| {
+                    let (state, message) = round2(
+                        &psi_context,
+                        psi_keys,
+                        state,
+                        messages_round2[&psi_keys.party_id].clone(),
+                        others(&messages_round2, psi_keys.party_id),
+                    )
+                    .unwrap();
+                    (state, (psi_keys.party_id, message))
+                })
+                .unzip();
+
+        // round3
+        let psi_outputs = izip!(&keys, states_round3)
+            .map(|(psi_keys, state)| {
+                round3(
+                    &psi_context,
+                    state,
+                    messages_round3[&psi_keys.party_id].clone(),
+                    others(&messages_round3, psi_keys.party_id),
+                )
+                .unwrap()
+            })
+            .collect_vec();
+
+        let expected_psi_output = plain_psi(&bit_vectors);
+        psi_outputs.iter().for_each(|psi_output| {
+            assert_eq!(&expected_psi_output, psi_output);
+        });
+    }
+
+    /// Runs the coin toss and `gen_keys` for parties `0..party_count`. Returns the keys and the
+    /// round 1 message of party 0, and the round 1 messages of its peers.
+    fn gen_round1_messages(
+        psi_context: &PsiContext,
+        mode: PsiMode,
+        party_count: PartyId,
+    ) -> (PsiKeys, MessageRound1, BTreeMap<PartyId, MessageRound1>) {
+        let crs = run_coin_toss(&(0..party_count).collect_vec());
+        let (psi_keys, message) = gen_keys(psi_context, &crs, mode, 0);
+        let other_messages = (1..party_count)
+            .map(|party_id| (party_id, gen_keys(psi_context, &crs, mode, party_id).1))
+            .collect();
+        (psi_keys, message, other_messages)
+    }
+
+    #[test]
+    fn round1_rejects_non_binary_input() {
+        let psi_parameters = PsiParameters::small();
+        let psi_context = PsiContext::new(psi_parameters.clone());
+        let (psi_keys, message, other_messages) =
+            gen_round1_messages(&psi_context, PsiMode::Intersection, 2);
+
+        let mut bit_vector = vec![0; psi_parameters.ring_size()];
+        bit_vector[7] = 2;
+        let result = round1(
+            &psi_context,
+            &psi_keys,
+            message,
+            other_messages,
+            &bit_vector,
+        );
+
+        assert_eq!(
+            result.err(),
+            Some(PsiError::InvalidInputBit {
+                index: 7,
+                value: 2,
+                bound: 2
+            })
+        );
+    }
+
+    #[test]
+    fn round2_rejects_mismatched_ciphertext_count() {
+        let psi_parameters = PsiParameters::small();
+        let psi_context = PsiContext::new(psi_parameters.clone());
+        let crs = run_coin_toss(&[0, 1]);
+        let (a_psi_keys, a_message_round1) = gen_keys(&psi_context, &crs, PsiMode::Intersection, 0);
+        let (b_psi_keys, b_message_round1) = gen_keys(&psi_context, &crs, PsiMode::Intersection, 1);
+
+        let (a_state_round2, a_message_round2) = round1(
+            &psi_context,
+            &a_psi_keys,
+            a_message_round1.clone(),
+            BTreeMap::from([(1, b_message_round1.clone())]),
+            &vec![1; psi_parameters.ring_size() * 2],
+        )
+        .unwrap();
+        let (_, b_message_round2) = round1(
+            &psi_context,
+            &b_psi_keys,
+            b_message_round1,
+            BTreeMap::from([(0, a_message_round1)]),
+            &vec![1; psi_parameters.ring_size()],
+        )
+        .unwrap();
+
+        let result = round2(
+            &psi_context,
+            &a_psi_keys,
+            a_state_round2,
+            a_message_round2,
+            BTreeMap::from([(1, b_message_round2)]),
+        );
+
+        assert_eq!(
+            result.err(),
+            Some(PsiError::CiphertextCountMismatch {
+                party_id: 1,
+                expected: 2,
+                found: 1
+            })
+        );
+    }
+
+    #[test]
+    fn round1_rejects_values_above_input_bound() {
+        let psi_parameters = PsiParameters::small().with_input_bound(4);
+        let psi_context = PsiContext::new(psi_parameters.clone());
+        let (psi_keys, message, other_messages) =
+            gen_round1_messages(&psi_context, PsiMode::Intersection, 2);
+
+        let mut input = vec![3; psi_parameters.ring_size()];
+        assert!(round1(
+            &psi_context,
+            &psi_keys,
+            message.clone(),
+            other_messages.clone(),
+            &input,
+        )
+        .is_ok());
+
+        input[5] = 4;
+        let result = round1(&psi_context, &psi_keys, message, other_messages, &input);
+
+        assert_eq!(
+            result.err(),
+            Some(PsiError::InvalidInputBit {
+                index: 5,
+                value: 4,
+                bound: 4
+            })
+        );
+    }
+
+    #[test]
+    fn round1_rejects_invalid_input_bound() {
+        let psi_context = PsiContext::new(PsiParameters::small().with_input_bound(1));
+        let (psi_keys, message, other_messages) =
+            gen_round1_messages(&psi_context, PsiMode::Intersection, 2);
+
+        let result = round1(&psi_context, &psi_keys, message, other_messages, &[0, 0]);
+
+        assert!(matches!(result, Err(PsiError::InvalidParameters(_))));
+    }
+
+    #[test]
+    fn round2_rejects_mismatched_domain_size() {
+        let psi_parameters = PsiParameters::small();
+        let psi_context = PsiContext::new(psi_parameters.clone());
+        let crs = run_coin_toss(&[0, 1]);
+        let (a_psi_keys, a_message_round1) = gen_keys(&psi_context, &crs, PsiMode::Intersection, 0);
+        let (b_psi_keys, b_message_round1) = gen_keys(&psi_context, &crs, PsiMode::Intersection, 1);
+
+        // both domains fit in one ciphertext
+        let (a_state_round2, a_message_round2) = round1(
+            &psi_context,
+            &a_psi_keys,
+            a_message_round1.clone(),
+            BTreeMap::from([(1, b_message_round1.clone())]),
+            &vec![1; psi_parameters.ring_size()],
+        )
+        .unwrap();
+        let (_, b_message_round2) = round1(
+            &psi_context,
+            &b_psi_keys,
+            b_message_round1,
+            BTreeMap::from([(0, a_message_round1)]),
+            &vec![1; psi_parameters.ring_size() - 1],
+        )
+        .unwrap();
+
+        let result = round2(
+            &psi_context,
+            &a_psi_keys,
+            a_state_round2,
+            a_message_round2,
+            BTreeMap::from([(1, b_message_round2)]),
+        );
+
+        assert_eq!(
+            result.err(),
+            Some(PsiError::DomainSizeMismatch {
+                party_id: 1,
+                expected: psi_parameters.ring_size(),
+                found: psi_parameters.ring_size() - 1
+            })
+        );
+    }
+
+    #[test]
+    fn round1_rejects_missing_peer() {
+        let psi_context = PsiContext::new(PsiParameters::small());
+        let (psi_keys, message, mut other_messages) =
+            gen_round1_messages(&psi_context, PsiMode::Intersection, 3);
+
+        other_messages.remove(&2);
+        let result = round1(&psi_context, &psi_keys, message, other_messages, &[1, 0, 1]);
+
+        assert_eq!(result.err(), Some(PsiError::MissingMessage { party_id: 2 }));
+    }
+
+    #[test]
+    fn round2_rejects_missing_peer() {
+        let psi_context = PsiContext::new(PsiParameters::small());
+        let (psi_keys, message, other_messages) =
+            gen_round1_messages(&psi_context, PsiMode::Intersection, 2);
+        let (state_round2, message_round2) =
+            round1(&psi_context, &psi_keys, message, other_messages, &[1, 0, 1]).unwrap();
+
+        let result = round2(
+            &psi_context,
+            &psi_keys,
+            state_round2,
+            message_round2,
+            BTreeMap::new(),
+        );
+
+        assert_eq!(result.err(), Some(PsiError::MissingMessage { party_id: 1 }));
+    }
+
+    #[test]
+    fn round3_rejects_missing_peer() {
+        let psi_context = PsiContext::new(PsiParameters::small());
+        let (mut states_round3, messages_round3) = run_rounds(
+            &psi_context,
+            PsiMode::Intersection,
+            &[vec![1, 0], vec![1, 1]],
+        );
+
+        let (party_id, state_round3) = states_round3.remove(0);
+        let result = round3(
+            &psi_context,
+            state_round3,
+            messages_round3[&party_id].clone(),
+            BTreeMap::new(),
+        );
+
+        assert_eq!(result.err(), Some(PsiError::MissingMessage { party_id: 1 }));
+    }
+
+    #[test]
+    fn round1_rejects_unbound_messages() {
+        let psi_context = PsiContext::new(PsiParameters::small());
+        let (psi_keys, message, mut other_messages) =
+            gen_round1_messages(&psi_context, PsiMode::Intersection, 2);
+        // a message of party 1 from another session
+        let (_, _, replayed_messages) = gen_round1_messages(&psi_context, PsiMode::Intersection, 2);
+
+        [
+            BTreeMap::from([(2, other_messages.remove(&1).unwrap())]),
+            replayed_messages,
+        ]
+        .into_iter()
+        .for_each(|other_messages| {
+            let result = round1(
+                &psi_context,
+                &psi_keys,
+                message.clone(),
+                other_messages,
+                &[1, 0, 1],
+            );
+            assert!(matches!(result, Err(PsiError::BindingMismatch { .. })));
+        });
+    }
+
+    #[test]
+    fn round1_rejects_mismatched_parameters() {
+        let crs = run_coin_toss(&[0, 1]);
+        let (a_psi_keys, a_message_round1) = gen_keys(
+            &PsiContext::new(PsiParameters::small()),
+            &crs,
+            PsiMode::Intersection,
+            0,
+        );
+        let (_, b_message_round1) = gen_keys(
+            &PsiContext::new(PsiParameters::medium()),
+            &crs,
+            PsiMode::Intersection,
+            1,
+        );
+
+        let result = round1(
+            &PsiContext::new(PsiParameters::small()),
+            &a_psi_keys,
+            a_message_round1,
+            BTreeMap::from([(1, b_message_round1)]),
+            &[1, 0, 1],
+        );
+
+        assert!(matches!(result, Err(PsiError::ParameterMismatch(_))));
+    }
+
+    #[test]
+    fn cardinality_works() {
+        let psi_parameters = PsiParameters::small();
+        let psi_context = PsiContext::new(psi_parameters.clone());
+        let vector_size = psi_parameters.ring_size() * 3;
+        let bit_vectors = (0..2)
+            .map(|_| random_bit_vector(1000, vector_size))
+            .collect_vec();
+
+        let (states_round3, messages_round3) =
+            run_rounds(&psi_context, PsiMode::Cardinality, &bit_vectors);
+        let cardinalities = states_round3
+            .into_iter()
+            .map(|(party_id, state)| {
+                round3_cardinality(
+                    &psi_context,
+                    state,
+                    messages_round3[&party_id].clone(),
+                    others(&messages_round3, party_id),
+                )
+                .unwrap()
+            })
+            .collect_vec();
+
+        let expected_cardinality = plain_psi(&bit_vectors).iter().sum::<u32>();
+        assert_eq!(cardinalities, vec![expected_cardinality; 2]);
+    }
+
+    #[test]
+    fn multi_party_cardinality_works() {
+        let psi_parameters = PsiParameters::medium();
+        let psi_context = PsiContext::new(psi_parameters.clone());
+        let vector_size = psi_parameters.ring_size() * 2;
+        let bit_vectors = (0..3)
+            .map(|_| random_bit_vector(3000, vector_size))
+            .collect_vec();
+
+        let (states_round3, messages_round3) =
+            run_rounds(&psi_context, PsiMode::Cardinality, &bit_vectors);
+        let expected_cardinality = plain_psi(&bit_vectors).iter().sum::<u32>();
+        states_round3.into_iter().for_each(|(party_id, state)| {
+            // only the total is decrypted
+            let psi_output = round3(
+                &psi_context,
+                state,
+                messages_round3[&party_id].clone(),
+                others(&messages_round3, party_id),
+            )
+            .unwrap();
+            assert_eq!(psi_output, vec![expected_cardinality]);
+        });
+    }
+
+    #[test]
+    fn round1_rejects_mismatched_mode() {
+        let psi_context = PsiContext::new(PsiParameters::small());
+        let crs = run_coin_toss(&[0, 1]);
+        let (a_psi_keys, a_message_round1) = gen_keys(&psi_context, &crs, PsiMode::Intersection, 0);
+        let (_, b_message_round1) = gen_keys(&psi_context, &crs, PsiMode::Cardinality, 1);
+
+        let result = round1(
+            &psi_context,
+            &a_psi_keys,
+            a_message_round1,
+            BTreeMap::from([(1, b_message_round1)]),
+            &[1, 0, 1],
+        );
+
+        assert!(matches!(result, Err(PsiError::ParameterMismatch(_))));
+    }
+
+    /// Runs a two party session in threshold mode on bit vectors intersecting in slots 3 and 4.
+    fn run_threshold(k: u32) -> Vec<Vec<u32>> {
+        // a threshold of 3 needs a depth of 4
+        let psi_parameters = PsiParameters::large();
+        let psi_context = PsiContext::new(psi_parameters.clone());
+        let vector_size = psi_parameters.ring_size();
+        let bit_vectors = [0..5, 3..8]
+            .into_iter()
+            .map(|ones| {
+                let mut bit_vector = vec![0; vector_size];
+                ones.for_each(|i| bit_vector[i] = 1);
+                bit_vector
+            })
+            .collect_vec();
+
+        let (states_round3, messages_round3) =
+            run_rounds(&psi_context, PsiMode::Threshold { k }, &bit_vectors);
+        states_round3
+            .into_iter()
+            .map(|(party_id, state)| {
+                round3(
+                    &psi_context,
+                    state,
+                    messages_round3[&party_id].clone(),
+                    others(&messages_round3, party_id),
+                )
+                .unwrap()
+            })
+            .collect_vec()
+    }
+
+    #[test]
+    fn threshold_reveals_intersection_at_k() {
+        let psi_outputs = run_threshold(2);
+
+        let mut expected_psi_output = vec![0; PsiParameters::large().ring_size()];
+        expected_psi_output[3] = 1;
+        expected_psi_output[4] = 1;
+        assert_eq!(psi_outputs, vec![expected_psi_output; 2]);
+    }
+
+    #[test]
+    fn threshold_hides_intersection_below_k() {
+        let psi_outputs = run_threshold(3);
+
+        psi_outputs.iter().for_each(|psi_output| {
+            assert!(psi_output.iter().all(|b| *b == 0));
+        });
+    }
+
+    #[test]
+    fn round1_rejects_insufficient_depth() {
+        let psi_context = PsiContext::new(PsiParameters::small());
+        let (psi_keys, message, other_messages) =
+            gen_round1_messages(&psi_context, PsiMode::Threshold { k: 4 }, 2);
+
+        let result = round1(&psi_context, &psi_keys, message, other_messages, &[1, 0, 1]);
+
+        assert!(matches!(result, Err(PsiError::InvalidParameters(_))));
+    }
+
+    #[test]
+    fn smudged_psi_works() {
+        let psi_parameters = PsiParameters::large().with_smudging_bits(60);
+        let psi_context = PsiContext::new(psi_parameters.clone());
+        let vector_size = psi_parameters.ring_size();
+        let bit_vectors = (0..2)
+            .map(|_| random_bit_vector(3000, vector_size))
+            .collect_vec();
+
+        let (states_round3, messages_round3) =
+            run_rounds(&psi_context, PsiMode::Intersection, &bit_vectors);
+        let expected_psi_output = plain_psi(&bit_vectors);
+        states_round3.into_iter().for_each(|(party_id, state)| {
+            let psi_output = round3(
+                &psi_context,
+                state,
+                messages_round3[&party_id].clone(),
+                others(&messages_round3, party_id),
+            )
+            .unwrap();
+            assert_eq!(expected_psi_output, psi_output);
+        });
+    }
+
+    #[test]
+    fn noise_budget_fits_smudging_after_relinearize() {
+        let psi_parameters = PsiParameters::large().with_smudging_bits(60);
+        let psi_context = PsiContext::new(psi_parameters.clone());
+        let crs = run_coin_toss(&[0]);
+
+        // with a single party the collective secret key is the party's own
+        let (psi_keys, message_round1) = gen_keys(&psi_context, &crs, PsiMode::Intersection, 0);
+        let (state_round2, message_round2) = round1(
+            &psi_context,
+            &psi_keys,
+            message_round1,
+            BTreeMap::new(),
+            &random_bit_vector(1000, psi_parameters.ring_size()),
+        )
+        .unwrap();
+        let rlk = CollectiveRlkGenerator::aggregate_shares_2(
+            psi_context.bfv_parameters(),
+            &[message_round2.share_rlk2],
+            state_round2.rlk_agg1_trimmed,
+            0,
+        );
+        let evaluator = psi_context.evaluator();
+        let evaluation_key = EvaluationKey::new_raw(&[0], vec![rlk], &[], &[], vec![]);
+
+        // the product of two parties
+        let ct = &message_round2.cts[0];
+        let ct_out = evaluator.relinearize(&evaluator.mul(ct, ct), &evaluation_key);
+        let noise_budget = evaluator
+            .measure_noise(&psi_keys.secret_key(psi_context.bfv_parameters()), &ct_out)
+            as usize;
+
+        // room for the flooding noise of both decryption shares
+        assert!(noise_budget > psi_parameters.smudging_bits() as usize + 2);
+    }
+
+    #[test]
+    fn round1_rejects_smudging_beyond_noise_budget() {
+        let psi_context = PsiContext::new(PsiParameters::small().with_smudging_bits(20));
+        let (psi_keys, message, other_messages) =
+            gen_round1_messages(&psi_context, PsiMode::Intersection, 2);
+
+        let result = round1(&psi_context, &psi_keys, message, other_messages, &[1, 0, 1]);
+
+        assert!(matches!(result, Err(PsiError::InvalidParameters(_))));
+    }
+
+    #[test]
+    fn psi_keys_wipe_works() {
+        let psi_context = PsiContext::new(PsiParameters::small());
+        let crs = run_coin_toss(&[0]);
+        let (mut psi_keys, _) = gen_keys(&psi_context, &crs, PsiMode::Intersection, 0);
+        assert!(!psi_keys.s.coefficients.is_empty());
+        assert!(!psi_keys.s_rlk.coefficients.is_empty());
+
+        // the path of drop and destroy
+        psi_keys.wipe();
+
+        assert!(psi_keys.s.coefficients.is_empty());
+        assert!(psi_keys.s_rlk.coefficients.is_empty());
+    }
+
+    #[test]
+    fn map_chunks_keeps_order() {
+        let chunks = (0..1000).collect_vec();
+
+        let doubled = map_chunks(&chunks, |chunk| chunk * 2);
+
+        assert_eq!(doubled, chunks.iter().map(|chunk| chunk * 2).collect_vec());
+    }
+
+    #[test]
+    fn map_chunks_with_rng_is_deterministic() {
+        let chunks = (0..100).collect_vec();
+        let sample = |seed| {
+            map_chunks_with_rng(&chunks, &mut ChaCha20Rng::seed_from_u64(seed), |rng, _| {
+                rng.next_u64()
+            })
+        };
+
+        assert_eq!(sample(1), sample(1));
+        assert_ne!(sample(1), sample(2));
+        assert!(sample(1).iter().all_unique());
+    }
 }
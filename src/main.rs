@@ -1 +1,737 @@
-fn main() {}
+//! Runs a PSI session by exchanging files.
+//!
+//! Every party keeps its progress in a state file and runs one subcommand per round. A
+//! subcommand reads the messages of the peers, passed as `--peer <party id>=<file>`, and writes
+//! the message to send to every peer to `--out`. Two parties run a session with:
+//!
+//! ```text
+//! mp-psi crs-commit --party-id 0 --parameters medium --state a.json --out a-commit.json
+//! mp-psi crs-reveal --state a.json --peer 1=b-commit.json --out a-reveal.json
+//! mp-psi keygen --state a.json --peer 1=b-reveal.json --out a-round1.json
+//! mp-psi round1 --state a.json --items a-items.txt --peer 1=b-round1.json --out a-round2.json
+//! mp-psi round2 --state a.json --peer 1=b-round2.json --out a-round3.json
+//! mp-psi round3 --state a.json --peer 1=b-round3.json
+//! mp-psi finalize --state a.json
+//! ```
+
+use std::{
+    collections::BTreeMap,
+    error::Error,
+    fs::{self, File, OpenOptions},
+    io::{BufReader, BufWriter, Write},
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+#[cfg(unix)]
+use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+
+use clap::{Args, Parser, Subcommand};
+use mp_psi::{
+    convert, convert_peer_messages, crs_commit, crs_derive, crs_reveal, gen_keys,
+    proto::{
+        MessageRound1Proto, MessageRound2Proto, MessageRound3Proto, PsiKeysProto, StateRound2Proto,
+        StateRound3Proto,
+    },
+    relay::RelayClient,
+    round1, round2, round3, round3_cardinality, BandwidthReport, ItemEncoder, ItemLookup,
+    MessageCrsCommit, MessageCrsReveal, PartyId, PsiContext, PsiKeys, PsiMode, PsiOutput,
+    PsiParameters, SessionCrs, StateCrsCommit, StateCrsReveal,
+};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+
+/// Fits the count of the cardinality and threshold modes for every preset.
+const DEFAULT_DOMAIN_SIZE: usize = 1 << 15;
+
+#[derive(Parser)]
+#[command(name = "mp-psi", about = "Multi-party PSI by exchanging files")]
+struct Cli {
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Starts a session and commits to the party's seed of the session CRS.
+    CrsCommit {
+        #[arg(long)]
+        party_id: PartyId,
+        /// `small`, `medium`, `large` or a JSON file of custom parameters.
+        #[arg(long, default_value = "medium")]
+        parameters: String,
+        /// `intersection`, `cardinality` or `threshold:<k>`.
+        #[arg(long, default_value = "intersection", value_parser = parse_mode)]
+        mode: PsiMode,
+        /// Number of slots the items are hashed into.
+        #[arg(long, default_value_t = DEFAULT_DOMAIN_SIZE)]
+        domain_size: usize,
+        /// State file to create.
+        #[arg(long)]
+        state: PathBuf,
+        /// File to write the commitment for the peers to.
+        #[arg(long)]
+        out: PathBuf,
+    },
+    /// Reveals the party's seed once the commitments of all peers are in.
+    CrsReveal(RoundArgs),
+    /// Derives the session CRS from the peers' seeds and generates the party's keys.
+    Keygen(RoundArgs),
+    /// Encrypts the party's items.
+    Round1 {
+        #[command(flatten)]
+        round: RoundArgs,
+        /// Items of the party, one per line.
+        #[arg(long)]
+        items: PathBuf,
+    },
+    /// Computes the intersection and the party's decryption shares.
+    Round2(RoundArgs),
+    /// Decrypts the output of the session into the state file.
+    Round3 {
+        #[arg(long)]
+        state: PathBuf,
+        #[arg(long = "peer", value_parser = parse_peer)]
+        peers: Vec<(PartyId, PathBuf)>,
+    },
+    /// Prints the items in the intersection, or its size in cardinality mode.
+    Finalize {
+        #[arg(long)]
+        state: PathBuf,
+        /// File to write the output to instead of stdout.
+        #[arg(long)]
+        out: Option<PathBuf>,
+    },
+    /// Leaves a message file on a relay for the peers to fetch.
+    RelayPut {
+        #[command(flatten)]
+        relay: RelayArgs,
+        #[arg(long)]
+        party_id: PartyId,
+        /// Recipient of the message. Repeat for every peer.
+        #[arg(long = "to", required = true)]
+        peers: Vec<PartyId>,
+        #[arg(long)]
+        file: PathBuf,
+    },
+    /// Fetches the message file of a peer from a relay, waiting until it arrives.
+    RelayGet {
+        #[command(flatten)]
+        relay: RelayArgs,
+        #[arg(long)]
+        party_id: PartyId,
+        /// Sender of the message.
+        #[arg(long)]
+        from: PartyId,
+        #[arg(long)]
+        out: PathBuf,
+        /// Seconds to wait for the message before giving up.
+        #[arg(long, default_value_t = 3600)]
+        timeout: u64,
+    },
+    /// Prints the bytes every party sends and receives in a session.
+    Bandwidth {
+        /// `small`, `medium`, `large` or a JSON file of custom parameters.
+        #[arg(long, default_value = "medium")]
+        parameters: String,
+        /// `intersection`, `cardinality` or `threshold:<k>`.
+        #[arg(long, default_value = "intersection", value_parser = parse_mode)]
+        mode: PsiMode,
+        #[arg(long, default_value_t = 2)]
+        parties: usize,
+        #[arg(long, default_value_t = DEFAULT_DOMAIN_SIZE)]
+        domain_size: usize,
+        /// Prints JSON instead of a table.
+        #[arg(long)]
+        json: bool,
+    },
+}
+
+#[derive(Args)]
+struct RelayArgs {
+    /// `host:port` of the relay.
+    #[arg(long)]
+    relay: String,
+    /// Name of the session, agreed on by all parties.
+    #[arg(long)]
+    session: String,
+    /// Index of the message in the session: 0 for the output of crs-commit, 1 for crs-reveal,
+    /// 2 for keygen, 3 for round1 and 4 for round2.
+    #[arg(long)]
+    seq: u64,
+}
+
+#[derive(Args)]
+struct RoundArgs {
+    /// State file of the party, updated in place.
+    #[arg(long)]
+    state: PathBuf,
+    /// Message of a peer as `<party id>=<file>`. Repeat for every peer.
+    #[arg(long = "peer", value_parser = parse_peer)]
+    peers: Vec<(PartyId, PathBuf)>,
+    /// File to write the message for the peers to.
+    #[arg(long)]
+    out: PathBuf,
+}
+
+/// Everything a party keeps between two subcommands.
+#[derive(Serialize, Deserialize)]
+struct PartyState {
+    party_id: PartyId,
+    parameters: PsiParameters,
+    mode: PsiMode,
+    domain_size: usize,
+    crs: Option<SessionCrs>,
+    psi_keys: Option<PsiKeysProto>,
+    lookup: Option<ItemLookup>,
+    stage: Stage,
+}
+
+/// The round the party is in, with its state and its own message of the previous round.
+#[derive(Serialize, Deserialize)]
+enum Stage {
+    CrsCommit {
+        state: StateCrsCommit,
+        message: MessageCrsCommit,
+    },
+    CrsReveal {
+        state: StateCrsReveal,
+        message: MessageCrsReveal,
+    },
+    Round1 {
+        message: MessageRound1Proto,
+    },
+    Round2 {
+        state: StateRound2Proto,
+        message: MessageRound2Proto,
+    },
+    Round3 {
+        state: StateRound3Proto,
+        message: MessageRound3Proto,
+    },
+    Finalize {
+        output: PsiOutput,
+    },
+}
+
+impl Stage {
+    /// Subcommand that continues from this stage.
+    fn next_command(&self) -> &'static str {
+        match self {
+            Stage::CrsCommit { .. } => "crs-reveal",
+            Stage::CrsReveal { .. } => "keygen",
+            Stage::Round1 { .. } => "round1",
+            Stage::Round2 { .. } => "round2",
+            Stage::Round3 { .. } => "round3",
+            Stage::Finalize { .. } => "finalize",
+        }
+    }
+}
+
+impl PartyState {
+    fn crs(&self) -> Result<&SessionCrs, Box<dyn Error>> {
+        self.crs
+            .as_ref()
+            .ok_or_else(|| "state file holds no session CRS".into())
+    }
+
+    fn psi_keys(&self, params: &bfv::BfvParameters) -> Result<PsiKeys, Box<dyn Error>> {
+        let psi_keys = self.psi_keys.as_ref().ok_or("state file holds no keys")?;
+        Ok(convert(psi_keys, params))
+    }
+}
+
+fn main() {
+    if let Err(e) = run(Cli::parse().command) {
+        eprintln!("error: {e}");
+        std::process::exit(1);
+    }
+}
+
+fn run(command: Command) -> Result<(), Box<dyn Error>> {
+    match command {
+        Command::CrsCommit {
+            party_id,
+            parameters,
+            mode,
+            domain_size,
+            state,
+            out,
+        } => {
+            let parameters = read_parameters(&parameters)?;
+            let (crs_state, message) = crs_commit(party_id);
+
+            write_json(&out, &message)?;
+            write_state(
+                &state,
+                &PartyState {
+                    party_id,
+                    parameters,
+                    mode,
+                    domain_size,
+                    crs: None,
+                    psi_keys: None,
+                    lookup: None,
+                    stage: Stage::CrsCommit {
+                        state: crs_state,
+                        message,
+                    },
+                },
+            )
+        }
+        Command::CrsReveal(args) => {
+            let mut party: PartyState = read_json(&args.state)?;
+            let (crs_state, message) = match party.stage {
+                Stage::CrsCommit { state, message } => {
+                    crs_reveal(state, message, read_peers(&args.peers)?)?
+                }
+                stage => return Err(wrong_stage("crs-reveal", &stage)),
+            };
+
+            write_json(&args.out, &message)?;
+            party.stage = Stage::CrsReveal {
+                state: crs_state,
+                message,
+            };
+            write_state(&args.state, &party)
+        }
+        Command::Keygen(args) => {
+            let mut party: PartyState = read_json(&args.state)?;
+            let crs = match party.stage {
+                Stage::CrsReveal { state, message } => {
+                    crs_derive(state, message, read_peers(&args.peers)?)?
+                }
+                stage => return Err(wrong_stage("keygen", &stage)),
+            };
+            let psi_context = PsiContext::new(party.parameters.clone());
+            let params = psi_context.bfv_parameters();
+            let (psi_keys, message) = gen_keys(&psi_context, &crs, party.mode, party.party_id);
+            let message: MessageRound1Proto = convert(&message, params);
+
+            write_json(&args.out, &message)?;
+            party.crs = Some(crs);
+            party.psi_keys = Some(convert(&psi_keys, params));
+            party.stage = Stage::Round1 { message };
+            write_state(&args.state, &party)
+        }
+        Command::Round1 { round: args, items } => {
+            let mut party: PartyState = read_json(&args.state)?;
+            let psi_context = PsiContext::new(party.parameters.clone());
+            let params = psi_context.bfv_parameters();
+            let psi_keys = party.psi_keys(params)?;
+            let encoder = ItemEncoder::for_session(party.domain_size, party.crs()?)?;
+            let own_message = match party.stage {
+                Stage::Round1 { message } => message,
+                stage => return Err(wrong_stage("round1", &stage)),
+            };
+            let other_messages: BTreeMap<PartyId, MessageRound1Proto> = read_peers(&args.peers)?;
+
+            let contents =
+                fs::read_to_string(&items).map_err(|e| format!("{}: {e}", items.display()))?;
+            let items = contents
+                .lines()
+                .filter(|l| !l.is_empty())
+                .collect::<Vec<_>>();
+            let (bit_vector, lookup) = encoder.encode(&items);
+
+            let (state, message) = round1(
+                &psi_context,
+                &psi_keys,
+                convert(&own_message, params),
+                convert_peer_messages(&psi_context, &own_message, &other_messages)?,
+                &bit_vector,
+            )?;
+            let message: MessageRound2Proto = convert(&message, params);
+
+            write_json(&args.out, &message)?;
+            party.lookup = Some(lookup);
+            party.stage = Stage::Round2 {
+                state: convert(&state, params),
+                message,
+            };
+            write_state(&args.state, &party)
+        }
+        Command::Round2(args) => {
+            let mut party: PartyState = read_json(&args.state)?;
+            let psi_context = PsiContext::new(party.parameters.clone());
+            let params = psi_context.bfv_parameters();
+            let psi_keys = party.psi_keys(params)?;
+            let (state, own_message) = match party.stage {
+                Stage::Round2 { state, message } => (state, message),
+                stage => return Err(wrong_stage("round2", &stage)),
+            };
+            let other_messages: BTreeMap<PartyId, MessageRound2Proto> = read_peers(&args.peers)?;
+
+            let (state, message) = round2(
+                &psi_context,
+                &psi_keys,
+                convert(&state, params),
+                convert(&own_message, params),
+                convert_peer_messages(&psi_context, &own_message, &other_messages)?,
+            )?;
+            let message: MessageRound3Proto = convert(&message, params);
+            // the decryption shares are out, so the keys are no longer needed
+            psi_keys.destroy();
+            party.psi_keys = None;
+
+            write_json(&args.out, &message)?;
+            party.stage = Stage::Round3 {
+                state: convert(&state, params),
+                message,
+            };
+            write_state(&args.state, &party)
+        }
+        Command::Round3 { state, peers } => {
+            let mut party: PartyState = read_json(&state)?;
+            let psi_context = PsiContext::new(party.parameters.clone());
+            let params = psi_context.bfv_parameters();
+            let (state_round3, own_message) = match party.stage {
+                Stage::Round3 { state, message } => (state, message),
+                stage => return Err(wrong_stage("round3", &stage)),
+            };
+            let other_messages: BTreeMap<PartyId, MessageRound3Proto> = read_peers(&peers)?;
+
+            let other_messages =
+                convert_peer_messages(&psi_context, &own_message, &other_messages)?;
+            let state_round3 = convert(&state_round3, params);
+            let own_message = convert(&own_message, params);
+            let output = match party.mode {
+                PsiMode::Cardinality => PsiOutput::Cardinality(round3_cardinality(
+                    &psi_context,
+                    state_round3,
+                    own_message,
+                    other_messages,
+                )?),
+                PsiMode::Intersection | PsiMode::Threshold { .. } => PsiOutput::Intersection(
+                    round3(&psi_context, state_round3, own_message, other_messages)?,
+                ),
+            };
+
+            party.stage = Stage::Finalize { output };
+            write_state(&state, &party)
+        }
+        Command::Finalize { state, out } => {
+            let party: PartyState = read_json(&state)?;
+            let lines = match &party.stage {
+                Stage::Finalize {
+                    output: PsiOutput::Intersection(psi_output),
+                } => party
+                    .lookup
+                    .as_ref()
+                    .ok_or("state file holds no items")?
+                    .intersection(psi_output)
+                    .into_iter()
+                    .map(|item| String::from_utf8_lossy(&item).into_owned())
+                    .collect::<Vec<_>>(),
+                Stage::Finalize {
+                    output: PsiOutput::Cardinality(cardinality),
+                } => vec![cardinality.to_string()],
+                stage => return Err(wrong_stage("finalize", stage)),
+            };
+
+            let mut writer: Box<dyn Write> = match out {
+                Some(path) => Box::new(BufWriter::new(File::create(path)?)),
+                None => Box::new(std::io::stdout().lock()),
+            };
+            lines
+                .iter()
+                .try_for_each(|line| writeln!(writer, "{line}"))?;
+            Ok(writer.flush()?)
+        }
+        Command::RelayPut {
+            relay,
+            party_id,
+            peers,
+            file,
+        } => {
+            let message = fs::read(&file).map_err(|e| format!("{}: {e}", file.display()))?;
+            let client = RelayClient::new(&relay.relay, &relay.session);
+            peers
+                .iter()
+                .try_for_each(|to| client.put(party_id, *to, relay.seq, &message))?;
+            Ok(())
+        }
+        Command::RelayGet {
+            relay,
+            party_id,
+            from,
+            out,
+            timeout,
+        } => {
+            let client = RelayClient::new(&relay.relay, &relay.session);
+            let message = client.wait(
+                from,
+                party_id,
+                relay.seq,
+                Duration::from_secs(1),
+                Duration::from_secs(timeout),
+            )?;
+            Ok(fs::write(&out, message).map_err(|e| format!("{}: {e}", out.display()))?)
+        }
+        Command::Bandwidth {
+            parameters,
+            mode,
+            parties,
+            domain_size,
+            json,
+        } => {
+            let psi_context = PsiContext::new(read_parameters(&parameters)?);
+            let report = BandwidthReport::generate(&psi_context, mode, parties, domain_size)?;
+            if json {
+                println!("{}", serde_json::to_string_pretty(&report)?);
+            } else {
+                println!("{report}");
+            }
+            Ok(())
+        }
+    }
+}
+
+/// Returns the preset named `parameters`, or reads custom parameters from the JSON file at
+/// that path.
+fn read_parameters(parameters: &str) -> Result<PsiParameters, Box<dyn Error>> {
+    match PsiParameters::preset(parameters) {
+        Some(preset) => Ok(preset),
+        None => read_json(Path::new(parameters)),
+    }
+}
+
+fn wrong_stage(command: &str, stage: &Stage) -> Box<dyn Error> {
+    format!(
+        "cannot run {command}, the state file expects {} next",
+        stage.next_command()
+    )
+    .into()
+}
+
+/// Parses `intersection`, `cardinality` or `threshold:<k>`.
+fn parse_mode(value: &str) -> Result<PsiMode, String> {
+    match value.split_once(':') {
+        None if value == "intersection" => Ok(PsiMode::Intersection),
+        None if value == "cardinality" => Ok(PsiMode::Cardinality),
+        Some(("threshold", k)) => k
+            .parse()
+            .map(|k| PsiMode::Threshold { k })
+            .map_err(|e| format!("invalid threshold {k}: {e}")),
+        _ => Err(format!("unknown mode {value}")),
+    }
+}
+
+/// Parses `<party id>=<file>`.
+fn parse_peer(value: &str) -> Result<(PartyId, PathBuf), String> {
+    let (party_id, path) = value
+        .split_once('=')
+        .ok_or_else(|| format!("expected <party id>=<file>, found {value}"))?;
+    let party_id = party_id
+        .parse()
+        .map_err(|e| format!("invalid party id {party_id}: {e}"))?;
+    Ok((party_id, PathBuf::from(path)))
+}
+
+fn read_json<T: DeserializeOwned>(path: &Path) -> Result<T, Box<dyn Error>> {
+    let file = File::open(path).map_err(|e| format!("{}: {e}", path.display()))?;
+    serde_json::from_reader(BufReader::new(file))
+        .map_err(|e| format!("{}: {e}", path.display()).into())
+}
+
+fn write_json<T: Serialize>(path: &Path, value: &T) -> Result<(), Box<dyn Error>> {
+    let file = File::create(path).map_err(|e| format!("{}: {e}", path.display()))?;
+    write_to(file, value)
+}
+
+/// Writes the state file, which only its owner may read since it holds the party's keys.
+fn write_state(path: &Path, party: &PartyState) -> Result<(), Box<dyn Error>> {
+    let mut options = OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    options.mode(0o600);
+    let file = options
+        .open(path)
+        .map_err(|e| format!("{}: {e}", path.display()))?;
+    // the mode only applies to new files, so restrict state files written by earlier versions
+    #[cfg(unix)]
+    file.set_permissions(fs::Permissions::from_mode(0o600))?;
+    write_to(file, party)
+}
+
+fn write_to<T: Serialize>(file: File, value: &T) -> Result<(), Box<dyn Error>> {
+    let mut writer = BufWriter::new(file);
+    serde_json::to_writer(&mut writer, value)?;
+    Ok(writer.flush()?)
+}
+
+fn read_peers<T: DeserializeOwned>(
+    peers: &[(PartyId, PathBuf)],
+) -> Result<BTreeMap<PartyId, T>, Box<dyn Error>> {
+    peers
+        .iter()
+        .map(|(party_id, path)| Ok((*party_id, read_json(path)?)))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::{thread_rng, Rng};
+
+    use super::*;
+
+    /// Creates an empty directory for the files of a test.
+    fn temp_dir() -> PathBuf {
+        let directory = std::env::temp_dir().join(format!(
+            "mp-psi-cli-{}-{}",
+            std::process::id(),
+            thread_rng().gen::<u64>()
+        ));
+        fs::create_dir(&directory).unwrap();
+        directory
+    }
+
+    #[test]
+    fn parse_mode_works() {
+        assert_eq!(parse_mode("intersection"), Ok(PsiMode::Intersection));
+        assert_eq!(parse_mode("cardinality"), Ok(PsiMode::Cardinality));
+        assert_eq!(parse_mode("threshold:2"), Ok(PsiMode::Threshold { k: 2 }));
+        assert!(parse_mode("threshold:two").is_err());
+        assert!(parse_mode("threshold").is_err());
+        assert!(parse_mode("union").is_err());
+    }
+
+    #[test]
+    fn parse_peer_works() {
+        assert_eq!(
+            parse_peer("1=b-round1.json"),
+            Ok((1, PathBuf::from("b-round1.json")))
+        );
+        // only the first `=` separates the party id from the path
+        assert_eq!(parse_peer("2=a=b.json"), Ok((2, PathBuf::from("a=b.json"))));
+        assert!(parse_peer("b-round1.json").is_err());
+        assert!(parse_peer("-1=b-round1.json").is_err());
+    }
+
+    #[test]
+    fn state_file_round_trip_works() {
+        let path = temp_dir().join("state.json");
+        let (state, message) = crs_commit(3);
+        let party = PartyState {
+            party_id: 3,
+            parameters: PsiParameters::small(),
+            mode: PsiMode::Threshold { k: 2 },
+            domain_size: 64,
+            crs: None,
+            psi_keys: None,
+            lookup: None,
+            stage: Stage::CrsCommit { state, message },
+        };
+
+        write_state(&path, &party).unwrap();
+        let party_back: PartyState = read_json(&path).unwrap();
+
+        assert_eq!(
+            serde_json::to_value(&party_back).unwrap(),
+            serde_json::to_value(&party).unwrap()
+        );
+        assert_eq!(party_back.stage.next_command(), "crs-reveal");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn state_file_is_private() {
+        let path = temp_dir().join("state.json");
+        // a state file left readable by an earlier version
+        fs::write(&path, "{}").unwrap();
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o644)).unwrap();
+
+        run(Command::CrsCommit {
+            party_id: 0,
+            parameters: "small".to_string(),
+            mode: PsiMode::Intersection,
+            domain_size: 64,
+            state: path.clone(),
+            out: path.with_file_name("commit.json"),
+        })
+        .unwrap();
+
+        let mode = fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+    }
+
+    #[test]
+    fn session_over_files_works() {
+        let directory = temp_dir();
+        let file = |party_id: PartyId, name: &str| directory.join(format!("{party_id}-{name}"));
+        let items = [["apple", "banana", "cherry"], ["banana", "cherry", "date"]];
+        let party_ids = [0, 1];
+        let round_args = |party_id: PartyId, input: &str, output: &str| RoundArgs {
+            state: file(party_id, "state.json"),
+            peers: vec![(1 - party_id, file(1 - party_id, input))],
+            out: file(party_id, output),
+        };
+
+        party_ids.iter().for_each(|party_id| {
+            fs::write(
+                file(*party_id, "items.txt"),
+                items[*party_id as usize].join("\n"),
+            )
+            .unwrap();
+            run(Command::CrsCommit {
+                party_id: *party_id,
+                parameters: "small".to_string(),
+                mode: PsiMode::Intersection,
+                // large enough that the items which differ rarely share a slot
+                domain_size: 8192,
+                state: file(*party_id, "state.json"),
+                out: file(*party_id, "commit.json"),
+            })
+            .unwrap();
+        });
+        party_ids.iter().for_each(|party_id| {
+            run(Command::CrsReveal(round_args(
+                *party_id,
+                "commit.json",
+                "reveal.json",
+            )))
+            .unwrap()
+        });
+        party_ids.iter().for_each(|party_id| {
+            run(Command::Keygen(round_args(
+                *party_id,
+                "reveal.json",
+                "round1.json",
+            )))
+            .unwrap()
+        });
+        party_ids.iter().for_each(|party_id| {
+            run(Command::Round1 {
+                round: round_args(*party_id, "round1.json", "round2.json"),
+                items: file(*party_id, "items.txt"),
+            })
+            .unwrap()
+        });
+        party_ids.iter().for_each(|party_id| {
+            run(Command::Round2(round_args(
+                *party_id,
+                "round2.json",
+                "round3.json",
+            )))
+            .unwrap()
+        });
+        party_ids.iter().for_each(|party_id| {
+            run(Command::Round3 {
+                state: file(*party_id, "state.json"),
+                peers: vec![(1 - party_id, file(1 - party_id, "round3.json"))],
+            })
+            .unwrap();
+            run(Command::Finalize {
+                state: file(*party_id, "state.json"),
+                out: Some(file(*party_id, "output.txt")),
+            })
+            .unwrap();
+        });
+
+        party_ids.iter().for_each(|party_id| {
+            let output = fs::read_to_string(file(*party_id, "output.txt")).unwrap();
+            let mut lines = output.lines().collect::<Vec<_>>();
+            lines.sort();
+            assert_eq!(lines, ["banana", "cherry"]);
+        });
+    }
+}
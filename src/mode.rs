@@ -0,0 +1,158 @@
+use bfv::{Ciphertext, Encoding, EvaluationKey, Evaluator, Plaintext};
+use itertools::Itertools;
+use serde::{Deserialize, Serialize};
+use traits::TryEncodingWithParameters;
+
+use crate::product_tree;
+
+/// What the parties learn at the end of a session. All parties must run the same mode.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum PsiMode {
+    /// Reveals which slots of the bit vector are in the intersection.
+    #[default]
+    Intersection,
+    /// Reveals only the number of slots in the intersection.
+    Cardinality,
+    /// Reveals the intersection if it holds at least `k` slots, and an all-zero output
+    /// otherwise.
+    Threshold { k: u32 },
+}
+
+/// Output of a session, depending on its mode.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub enum PsiOutput {
+    /// One value per slot of the domain, 1 for the slots in the intersection.
+    Intersection(Vec<u32>),
+    /// Number of slots in the intersection.
+    Cardinality(u32),
+}
+
+impl PsiMode {
+    /// Rotations the evaluation key of the session must support.
+    pub(crate) fn rotations(&self, ring_size: usize) -> Vec<isize> {
+        match self {
+            PsiMode::Intersection => vec![],
+            PsiMode::Cardinality | PsiMode::Threshold { .. } => slot_sum_rotations(ring_size),
+        }
+    }
+
+    /// Whether `round2` computes the intersection size, which is only correct below the
+    /// plaintext modulus.
+    pub(crate) fn counts(&self) -> bool {
+        !matches!(self, PsiMode::Intersection)
+    }
+
+    /// Multiplicative depth of `round2` for `party_count` parties.
+    pub(crate) fn depth(&self, party_count: usize) -> usize {
+        let product_depth = ceil_log2(party_count);
+        match self {
+            PsiMode::Intersection | PsiMode::Cardinality => product_depth,
+            // the mask polynomial of degree k, then the product with the intersection
+            PsiMode::Threshold { k } => product_depth + ceil_log2(*k as usize) + 1,
+        }
+    }
+}
+
+pub(crate) fn ceil_log2(value: usize) -> usize {
+    (usize::BITS - value.saturating_sub(1).leading_zeros()) as usize
+}
+
+/// bfv arranges the slots in two rows of `ring_size / 2` and treats a rotation by
+/// `2 * ring_size - 1` as swapping the rows.
+fn row_swap(ring_size: usize) -> isize {
+    (2 * ring_size - 1) as isize
+}
+
+/// Column rotations by every power of two below the row length, then the row swap.
+fn slot_sum_rotations(ring_size: usize) -> Vec<isize> {
+    (0..)
+        .map(|i| 1usize << i)
+        .take_while(|r| *r < ring_size / 2)
+        .map(|r| r as isize)
+        .chain(std::iter::once(row_swap(ring_size)))
+        .collect_vec()
+}
+
+/// Adds up all slots of `ct` with `log2(ring_size)` rotations. Every slot of the result holds
+/// the sum.
+pub(crate) fn sum_slots(
+    evaluator: &Evaluator,
+    evaluation_key: &EvaluationKey,
+    ct: Ciphertext,
+    ring_size: usize,
+) -> Ciphertext {
+    slot_sum_rotations(ring_size)
+        .into_iter()
+        .fold(ct, |acc, rotate_by| {
+            let rotated = evaluator.rotate(&acc, rotate_by, evaluation_key);
+            evaluator.add(&acc, &rotated)
+        })
+}
+
+/// Number of slots set in any of the chunk products `cts_res`, in every slot of the result.
+pub(crate) fn intersection_size(
+    evaluator: &Evaluator,
+    evaluation_key: &EvaluationKey,
+    cts_res: &[Ciphertext],
+    ring_size: usize,
+) -> Ciphertext {
+    let total = cts_res
+        .iter()
+        .cloned()
+        .reduce(|acc, c| evaluator.add(&acc, &c))
+        .expect("at least one chunk");
+    sum_slots(evaluator, evaluation_key, total, ring_size)
+}
+
+/// Evaluates `c (c - 1) ... (c - k + 1)` on the intersection size `c`.
+///
+/// As long as `c` is below the plaintext modulus `t`, the product is zero exactly when `c < k`:
+/// otherwise its factors are `k` distinct values in `[1, t)`, none of which divides the prime
+/// `t`.
+pub(crate) fn threshold_mask(
+    evaluator: &Evaluator,
+    evaluation_key: &EvaluationKey,
+    size: &Ciphertext,
+    k: u32,
+    ring_size: usize,
+) -> Ciphertext {
+    let factors = (0..k)
+        .map(|j| {
+            if j == 0 {
+                return size.clone();
+            }
+            let pt = Plaintext::try_encoding_with_parameters(
+                vec![j; ring_size],
+                evaluator.params(),
+                Encoding::default(),
+            );
+            evaluator.sub_plaintext(size, &pt)
+        })
+        .collect_vec();
+    product_tree(evaluator, evaluation_key, factors)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn slot_sum_rotations_cover_all_slots() {
+        let ring_size = 1 << 11;
+        let rotations = slot_sum_rotations(ring_size);
+
+        assert_eq!(rotations.len(), 11);
+        assert_eq!(rotations[..10].iter().sum::<isize>(), 1023);
+        assert_eq!(*rotations.last().unwrap(), row_swap(ring_size));
+        assert!(PsiMode::Intersection.rotations(ring_size).is_empty());
+    }
+
+    #[test]
+    fn depth_works() {
+        assert_eq!(PsiMode::Intersection.depth(1), 0);
+        assert_eq!(PsiMode::Intersection.depth(2), 1);
+        assert_eq!(PsiMode::Cardinality.depth(3), 2);
+        assert_eq!(PsiMode::Threshold { k: 1 }.depth(2), 2);
+        assert_eq!(PsiMode::Threshold { k: 5 }.depth(4), 6);
+    }
+}
@@ -0,0 +1,332 @@
+use bfv::BfvParameters;
+use serde::{Deserialize, Serialize};
+
+use crate::PsiError;
+
+/// BFV parameters of a PSI session. All parties must use the same parameters.
+///
+/// The domain is split into chunks of `ring_size` slots, one ciphertext per chunk. Larger rings
+/// hold more slots per ciphertext and leave room for the deeper product tree of sessions with
+/// more parties, at the cost of bigger messages.
+///
+/// Decryption shares carry flooding noise of `smudging_bits` bits, which hides the secret key
+/// of each party from the others once the output is decrypted. The presets leave it off, see
+/// [`PsiParameters::with_smudging_bits`].
+///
+/// Inputs hold one value below `input_bound` per slot. The presets take bit vectors, see
+/// [`PsiParameters::with_input_bound`].
+///
+/// Deserialized parameters go through the same checks as [`PsiParameters::custom`].
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+#[serde(try_from = "PsiParametersRaw")]
+pub struct PsiParameters {
+    ring_size: usize,
+    ciphertext_moduli: Vec<u64>,
+    extension_moduli: Vec<u64>,
+    plaintext_modulus: u64,
+    special_moduli: Vec<u64>,
+    smudging_bits: u32,
+    input_bound: u32,
+}
+
+/// Serialized form of [`PsiParameters`], checked before it is accepted.
+#[derive(Deserialize)]
+struct PsiParametersRaw {
+    ring_size: usize,
+    ciphertext_moduli: Vec<u64>,
+    extension_moduli: Vec<u64>,
+    plaintext_modulus: u64,
+    special_moduli: Vec<u64>,
+    #[serde(default)]
+    smudging_bits: u32,
+    #[serde(default = "default_input_bound")]
+    input_bound: u32,
+}
+
+impl TryFrom<PsiParametersRaw> for PsiParameters {
+    type Error = PsiError;
+
+    fn try_from(raw: PsiParametersRaw) -> Result<Self, Self::Error> {
+        Ok(PsiParameters::custom(
+            raw.ring_size,
+            raw.ciphertext_moduli,
+            raw.extension_moduli,
+            raw.plaintext_modulus,
+            raw.special_moduli,
+        )?
+        .with_smudging_bits(raw.smudging_bits)
+        .with_input_bound(raw.input_bound))
+    }
+}
+
+/// Inputs are bit vectors unless the parameters say otherwise.
+const DEFAULT_INPUT_BOUND: u32 = 2;
+
+fn default_input_bound() -> u32 {
+    DEFAULT_INPUT_BOUND
+}
+
+impl PsiParameters {
+    /// Ring size 2^11 with the moduli the crate has always used. Fits two parties.
+    ///
+    /// The key modulus is 66 bits, above the 54 bits the homomorphic encryption standard allows
+    /// for 128-bit security at this ring size. Prefer [`PsiParameters::medium`] for deployments.
+    pub fn small() -> PsiParameters {
+        PsiParameters {
+            ring_size: 1 << 11,
+            ciphertext_moduli: vec![1032193, 1073692673],
+            extension_moduli: vec![995329, 1073668097],
+            plaintext_modulus: 40961,
+            special_moduli: vec![61441],
+            smudging_bits: 0,
+            input_bound: DEFAULT_INPUT_BOUND,
+        }
+    }
+
+    /// Ring size 2^12 with a 107-bit key modulus, which gives 128-bit security.
+    pub fn medium() -> PsiParameters {
+        PsiParameters {
+            ring_size: 1 << 12,
+            ciphertext_moduli: vec![1073692673, 1073668097, 1073651713],
+            extension_moduli: vec![1073643521, 1073569793, 1073479681],
+            plaintext_modulus: 40961,
+            special_moduli: vec![163841],
+            smudging_bits: 0,
+            input_bound: DEFAULT_INPUT_BOUND,
+        }
+    }
+
+    /// Ring size 2^13 with a 210-bit key modulus, which gives 128-bit security.
+    pub fn large() -> PsiParameters {
+        PsiParameters {
+            ring_size: 1 << 13,
+            ciphertext_moduli: vec![
+                1073692673, 1073643521, 1073479681, 1073430529, 1073299457, 1073233921,
+            ],
+            extension_moduli: vec![
+                1073135617, 1073053697, 1072857089, 1072611329, 1072496641, 1072218113,
+            ],
+            plaintext_modulus: 65537,
+            special_moduli: vec![1073184769],
+            smudging_bits: 0,
+            input_bound: DEFAULT_INPUT_BOUND,
+        }
+    }
+
+    /// Looks up a preset by name: `small`, `medium` or `large`.
+    pub fn preset(name: &str) -> Option<PsiParameters> {
+        match name {
+            "small" => Some(PsiParameters::small()),
+            "medium" => Some(PsiParameters::medium()),
+            "large" => Some(PsiParameters::large()),
+            _ => None,
+        }
+    }
+
+    /// Builds custom parameters.
+    ///
+    /// Only checks that the moduli support batching at the given ring size. Choosing moduli
+    /// that are secure and large enough for the number of parties is up to the caller.
+    pub fn custom(
+        ring_size: usize,
+        ciphertext_moduli: Vec<u64>,
+        extension_moduli: Vec<u64>,
+        plaintext_modulus: u64,
+        special_moduli: Vec<u64>,
+    ) -> Result<PsiParameters, PsiError> {
+        if !ring_size.is_power_of_two() || ring_size < 2 {
+            return Err(PsiError::InvalidParameters(format!(
+                "ring size {ring_size} is not a power of two"
+            )));
+        }
+        if ciphertext_moduli.is_empty() || extension_moduli.is_empty() || special_moduli.is_empty()
+        {
+            return Err(PsiError::InvalidParameters(
+                "moduli chains must not be empty".to_string(),
+            ));
+        }
+
+        let two_n = 2 * ring_size as u64;
+        if let Some(modulus) = ciphertext_moduli
+            .iter()
+            .chain(extension_moduli.iter())
+            .chain(special_moduli.iter())
+            .chain(std::iter::once(&plaintext_modulus))
+            .find(|q| **q % two_n != 1)
+        {
+            return Err(PsiError::InvalidParameters(format!(
+                "modulus {modulus} is not 1 mod {two_n}"
+            )));
+        }
+
+        Ok(PsiParameters {
+            ring_size,
+            ciphertext_moduli,
+            extension_moduli,
+            plaintext_modulus,
+            special_moduli,
+            smudging_bits: 0,
+            input_bound: DEFAULT_INPUT_BOUND,
+        })
+    }
+
+    /// Floods every decryption share with noise of `smudging_bits` bits.
+    ///
+    /// The noise of all shares adds up in the decrypted ciphertext, so it has to fit the noise
+    /// budget left after the multiplications of the session, which `round1` checks. For 40 bits
+    /// of statistical security the flooding noise should exceed the ciphertext noise by 40 bits,
+    /// which only the `large` preset has room for.
+    pub fn with_smudging_bits(mut self, smudging_bits: u32) -> PsiParameters {
+        self.smudging_bits = smudging_bits;
+        self
+    }
+
+    /// Accepts inputs with values below `input_bound` instead of bits only.
+    ///
+    /// `round1` rejects inputs holding a larger value, and bounds outside `2..=t`. The output of
+    /// a slot is the product of the inputs of all parties modulo `t`, so it only tells the
+    /// values apart while that product stays below `t`.
+    pub fn with_input_bound(mut self, input_bound: u32) -> PsiParameters {
+        self.input_bound = input_bound;
+        self
+    }
+
+    /// Number of slots in a ciphertext.
+    pub fn ring_size(&self) -> usize {
+        self.ring_size
+    }
+
+    pub fn plaintext_modulus(&self) -> u64 {
+        self.plaintext_modulus
+    }
+
+    pub fn smudging_bits(&self) -> u32 {
+        self.smudging_bits
+    }
+
+    /// Exclusive upper bound on the values of an input.
+    pub fn input_bound(&self) -> u32 {
+        self.input_bound
+    }
+
+    /// Moduli of the key switching basis: the ciphertext moduli followed by the special moduli.
+    pub(crate) fn key_moduli(&self) -> Vec<u64> {
+        self.ciphertext_moduli
+            .iter()
+            .chain(self.special_moduli.iter())
+            .copied()
+            .collect()
+    }
+
+    /// Estimated number of sequential multiplications after which ciphertexts still decrypt
+    /// correctly.
+    ///
+    /// Fresh ciphertexts take up about `log2(t) + 4` bits of the ciphertext modulus and every
+    /// multiplication about `log2(t) + log2(N) + 3` more. The estimate matches the presets: the
+    /// small one holds the product of two parties, the medium one the product of four.
+    pub fn multiplicative_depth(&self) -> usize {
+        self.noise_budget(0) / self.level_bits()
+    }
+
+    /// Estimated bits of noise that ciphertexts can still absorb after `depth` sequential
+    /// multiplications, following the same estimate as [`PsiParameters::multiplicative_depth`].
+    pub fn noise_budget(&self, depth: usize) -> usize {
+        let modulus_bits = self
+            .ciphertext_moduli
+            .iter()
+            .map(|q| bit_length(*q))
+            .sum::<usize>();
+        let fresh_bits = bit_length(self.plaintext_modulus) + 4;
+        modulus_bits.saturating_sub(fresh_bits + depth * self.level_bits())
+    }
+
+    fn level_bits(&self) -> usize {
+        bit_length(self.plaintext_modulus) + self.ring_size.trailing_zeros() as usize + 3
+    }
+
+    /// Builds the bfv parameters, including the NTT tables and hybrid key switching data.
+    pub fn bfv_parameters(&self) -> BfvParameters {
+        let mut params = BfvParameters::new_with_primes(
+            self.ciphertext_moduli.clone(),
+            self.extension_moduli.clone(),
+            self.plaintext_modulus,
+            self.ring_size,
+        );
+        params.enable_hybrid_key_switching_with_prime(self.special_moduli.clone());
+        params.enable_pke();
+        params
+    }
+}
+
+pub(crate) fn bit_length(value: u64) -> usize {
+    (u64::BITS - value.leading_zeros()) as usize
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn presets_are_valid() {
+        ["small", "medium", "large"].iter().for_each(|name| {
+            let preset = PsiParameters::preset(name).unwrap();
+            let custom = PsiParameters::custom(
+                preset.ring_size,
+                preset.ciphertext_moduli.clone(),
+                preset.extension_moduli.clone(),
+                preset.plaintext_modulus,
+                preset.special_moduli.clone(),
+            );
+            assert_eq!(custom, Ok(preset));
+        });
+    }
+
+    #[test]
+    fn multiplicative_depth_works() {
+        assert_eq!(PsiParameters::small().multiplicative_depth(), 1);
+        assert_eq!(PsiParameters::medium().multiplicative_depth(), 2);
+        assert_eq!(PsiParameters::large().multiplicative_depth(), 4);
+        assert_eq!(PsiParameters::small().noise_budget(1), 0);
+        assert_eq!(PsiParameters::large().noise_budget(1), 126);
+    }
+
+    #[test]
+    fn input_bound_defaults_to_bits() {
+        let mut value = serde_json::to_value(PsiParameters::small().with_input_bound(4)).unwrap();
+        value.as_object_mut().unwrap().remove("input_bound");
+
+        let psi_parameters: PsiParameters = serde_json::from_value(value).unwrap();
+
+        assert_eq!(psi_parameters.input_bound(), 2);
+        assert_eq!(psi_parameters, PsiParameters::small());
+    }
+
+    #[test]
+    fn deserialize_rejects_invalid_ring_size() {
+        let mut value = serde_json::to_value(PsiParameters::small()).unwrap();
+        value["ring_size"] = serde_json::json!(3000);
+
+        let result = serde_json::from_value::<PsiParameters>(value);
+
+        assert_eq!(
+            result.unwrap_err().to_string(),
+            "invalid parameters: ring size 3000 is not a power of two"
+        );
+    }
+
+    #[test]
+    fn custom_rejects_moduli_without_batching() {
+        let small = PsiParameters::small();
+
+        // 995329 is 1 mod 2^12 but not mod 2^13
+        let result = PsiParameters::custom(
+            1 << 12,
+            small.ciphertext_moduli,
+            small.extension_moduli,
+            small.plaintext_modulus,
+            small.special_moduli,
+        );
+
+        assert!(matches!(result, Err(PsiError::InvalidParameters(_))));
+    }
+}
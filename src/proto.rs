@@ -0,0 +1,464 @@
+//! Serializable counterparts of the round messages and states.
+//!
+//! The bfv types only implement serde through their `*Proto` representation, which requires the
+//! `BfvParameters` to convert back and forth. Every message and state of the protocol therefore
+//! has a `*Proto` twin deriving `Serialize`/`Deserialize`, and converts to it with [`convert`].
+//!
+//! [`convert`]: crate::convert
+
+use bfv::{
+    BfvParameters, CiphertextProto, CollectiveDecryptionShare, CollectiveDecryptionShareProto,
+    CollectivePublicKeyShareProto, CollectiveRlkAggTrimmedShare1Proto, CollectiveRlkShare1Proto,
+    CollectiveRlkShare2Proto, CollectiveRtgShareProto, GaloisKeyProto, SecretKeyProto,
+};
+use itertools::Itertools;
+use serde::{Deserialize, Serialize};
+use traits::{TryFromWithLevelledParameters, TryFromWithParameters};
+
+use crate::{
+    convert, wipe_secret_key, MessageRound1, MessageRound1Reveal, MessageRound2, MessageRound3,
+    MessageRound3Reveal, PartyId, PsiKeys, PsiMode, PsiParameters, SessionCrs, StateRound2,
+    StateRound3,
+};
+
+#[derive(Serialize, Deserialize, Clone, PartialEq)]
+pub struct PsiKeysProto {
+    party_id: PartyId,
+    crs: SessionCrs,
+    mode: PsiMode,
+    s: SecretKeyProto,
+    s_rlk: SecretKeyProto,
+}
+
+impl Drop for PsiKeysProto {
+    fn drop(&mut self) {
+        wipe_secret_key(&mut self.s);
+        wipe_secret_key(&mut self.s_rlk);
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct MessageRound1Proto {
+    pub(crate) party_id: PartyId,
+    session_id: [u8; 32],
+    parameters: PsiParameters,
+    mode: PsiMode,
+    pub(crate) share_pk: CollectivePublicKeyShareProto,
+    pub(crate) share_rlk1: CollectiveRlkShare1Proto,
+    pub(crate) share_rtgs: Vec<CollectiveRtgShareProto>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct MessageRound1RevealProto {
+    salt: [u8; 32],
+    pub(crate) message: MessageRound1Proto,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct StateRound2Proto {
+    party_id: PartyId,
+    party_ids: Vec<PartyId>,
+    rlk_agg1_trimmed: CollectiveRlkAggTrimmedShare1Proto,
+    rtgs: Vec<GaloisKeyProto>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct MessageRound2Proto {
+    domain_size: usize,
+    pub(crate) share_rlk2: CollectiveRlkShare2Proto,
+    pub(crate) cts: Vec<CiphertextProto>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct MessageRound3Proto {
+    pub(crate) decryption_shares: Vec<CollectiveDecryptionShareProto>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct MessageRound3RevealProto {
+    salt: [u8; 32],
+    pub(crate) message: MessageRound3Proto,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct StateRound3Proto {
+    party_id: PartyId,
+    party_ids: Vec<PartyId>,
+    mode: PsiMode,
+    domain_size: usize,
+    cts_res: Vec<CiphertextProto>,
+}
+
+impl TryFromWithParameters for PsiKeysProto {
+    type Value = PsiKeys;
+    type Parameters = BfvParameters;
+
+    fn try_from_with_parameters(value: &PsiKeys, _parameters: &BfvParameters) -> Self {
+        PsiKeysProto {
+            party_id: value.party_id,
+            crs: value.crs.clone(),
+            mode: value.mode,
+            s: value.s.clone(),
+            s_rlk: value.s_rlk.clone(),
+        }
+    }
+}
+
+impl TryFromWithParameters for PsiKeys {
+    type Value = PsiKeysProto;
+    type Parameters = BfvParameters;
+
+    fn try_from_with_parameters(value: &PsiKeysProto, _parameters: &BfvParameters) -> Self {
+        PsiKeys {
+            party_id: value.party_id,
+            crs: value.crs.clone(),
+            mode: value.mode,
+            s: value.s.clone(),
+            s_rlk: value.s_rlk.clone(),
+        }
+    }
+}
+
+impl TryFromWithParameters for MessageRound1Proto {
+    type Value = MessageRound1;
+    type Parameters = BfvParameters;
+
+    fn try_from_with_parameters(value: &MessageRound1, parameters: &BfvParameters) -> Self {
+        MessageRound1Proto {
+            party_id: value.party_id,
+            session_id: value.session_id,
+            parameters: value.parameters.clone(),
+            mode: value.mode,
+            share_pk: convert(&value.share_pk, parameters),
+            share_rlk1: convert(&value.share_rlk1, parameters),
+            share_rtgs: value
+                .share_rtgs
+                .iter()
+                .map(|share| convert(share, parameters))
+                .collect_vec(),
+        }
+    }
+}
+
+impl TryFromWithParameters for MessageRound1 {
+    type Value = MessageRound1Proto;
+    type Parameters = BfvParameters;
+
+    fn try_from_with_parameters(value: &MessageRound1Proto, parameters: &BfvParameters) -> Self {
+        MessageRound1 {
+            party_id: value.party_id,
+            session_id: value.session_id,
+            parameters: value.parameters.clone(),
+            mode: value.mode,
+            share_pk: convert(&value.share_pk, parameters),
+            share_rlk1: convert(&value.share_rlk1, parameters),
+            share_rtgs: value
+                .share_rtgs
+                .iter()
+                .map(|share| convert(share, parameters))
+                .collect_vec(),
+        }
+    }
+}
+
+impl TryFromWithParameters for StateRound2Proto {
+    type Value = StateRound2;
+    type Parameters = BfvParameters;
+
+    fn try_from_with_parameters(value: &StateRound2, parameters: &BfvParameters) -> Self {
+        StateRound2Proto {
+            party_id: value.party_id,
+            party_ids: value.party_ids.clone(),
+            rlk_agg1_trimmed: convert(&value.rlk_agg1_trimmed, parameters),
+            rtgs: value
+                .rtgs
+                .iter()
+                .map(|rtg| convert(rtg, parameters))
+                .collect_vec(),
+        }
+    }
+}
+
+impl TryFromWithParameters for StateRound2 {
+    type Value = StateRound2Proto;
+    type Parameters = BfvParameters;
+
+    fn try_from_with_parameters(value: &StateRound2Proto, parameters: &BfvParameters) -> Self {
+        StateRound2 {
+            party_id: value.party_id,
+            party_ids: value.party_ids.clone(),
+            rlk_agg1_trimmed: convert(&value.rlk_agg1_trimmed, parameters),
+            rtgs: value
+                .rtgs
+                .iter()
+                .map(|rtg| convert(rtg, parameters))
+                .collect_vec(),
+        }
+    }
+}
+
+impl TryFromWithParameters for MessageRound2Proto {
+    type Value = MessageRound2;
+    type Parameters = BfvParameters;
+
+    fn try_from_with_parameters(value: &MessageRound2, parameters: &BfvParameters) -> Self {
+        MessageRound2Proto {
+            domain_size: value.domain_size,
+            share_rlk2: convert(&value.share_rlk2, parameters),
+            cts: value
+                .cts
+                .iter()
+                .map(|c| convert(c, parameters))
+                .collect_vec(),
+        }
+    }
+}
+
+impl TryFromWithParameters for MessageRound2 {
+    type Value = MessageRound2Proto;
+    type Parameters = BfvParameters;
+
+    fn try_from_with_parameters(value: &MessageRound2Proto, parameters: &BfvParameters) -> Self {
+        MessageRound2 {
+            domain_size: value.domain_size,
+            share_rlk2: convert(&value.share_rlk2, parameters),
+            cts: value
+                .cts
+                .iter()
+                .map(|c| convert(c, parameters))
+                .collect_vec(),
+        }
+    }
+}
+
+impl TryFromWithParameters for MessageRound3Proto {
+    type Value = MessageRound3;
+    type Parameters = BfvParameters;
+
+    fn try_from_with_parameters(value: &MessageRound3, parameters: &BfvParameters) -> Self {
+        MessageRound3Proto {
+            decryption_shares: value
+                .decryption_shares
+                .iter()
+                .map(|share| {
+                    CollectiveDecryptionShareProto::try_from_with_levelled_parameters(
+                        share, parameters, 0,
+                    )
+                })
+                .collect_vec(),
+        }
+    }
+}
+
+impl TryFromWithParameters for MessageRound3 {
+    type Value = MessageRound3Proto;
+    type Parameters = BfvParameters;
+
+    fn try_from_with_parameters(value: &MessageRound3Proto, parameters: &BfvParameters) -> Self {
+        MessageRound3 {
+            decryption_shares: value
+                .decryption_shares
+                .iter()
+                .map(|share| {
+                    CollectiveDecryptionShare::try_from_with_levelled_parameters(
+                        share, parameters, 0,
+                    )
+                })
+                .collect_vec(),
+        }
+    }
+}
+
+impl TryFromWithParameters for MessageRound1RevealProto {
+    type Value = MessageRound1Reveal;
+    type Parameters = BfvParameters;
+
+    fn try_from_with_parameters(value: &MessageRound1Reveal, parameters: &BfvParameters) -> Self {
+        MessageRound1RevealProto {
+            salt: value.salt,
+            message: convert(&value.message, parameters),
+        }
+    }
+}
+
+impl TryFromWithParameters for MessageRound1Reveal {
+    type Value = MessageRound1RevealProto;
+    type Parameters = BfvParameters;
+
+    fn try_from_with_parameters(
+        value: &MessageRound1RevealProto,
+        parameters: &BfvParameters,
+    ) -> Self {
+        MessageRound1Reveal {
+            salt: value.salt,
+            message: convert(&value.message, parameters),
+        }
+    }
+}
+
+impl TryFromWithParameters for MessageRound3RevealProto {
+    type Value = MessageRound3Reveal;
+    type Parameters = BfvParameters;
+
+    fn try_from_with_parameters(value: &MessageRound3Reveal, parameters: &BfvParameters) -> Self {
+        MessageRound3RevealProto {
+            salt: value.salt,
+            message: convert(&value.message, parameters),
+        }
+    }
+}
+
+impl TryFromWithParameters for MessageRound3Reveal {
+    type Value = MessageRound3RevealProto;
+    type Parameters = BfvParameters;
+
+    fn try_from_with_parameters(
+        value: &MessageRound3RevealProto,
+        parameters: &BfvParameters,
+    ) -> Self {
+        MessageRound3Reveal {
+            salt: value.salt,
+            message: convert(&value.message, parameters),
+        }
+    }
+}
+
+impl TryFromWithParameters for StateRound3Proto {
+    type Value = StateRound3;
+    type Parameters = BfvParameters;
+
+    fn try_from_with_parameters(value: &StateRound3, parameters: &BfvParameters) -> Self {
+        StateRound3Proto {
+            party_id: value.party_id,
+            party_ids: value.party_ids.clone(),
+            mode: value.mode,
+            domain_size: value.domain_size,
+            cts_res: value
+                .cts_res
+                .iter()
+                .map(|c| convert(c, parameters))
+                .collect_vec(),
+        }
+    }
+}
+
+impl TryFromWithParameters for StateRound3 {
+    type Value = StateRound3Proto;
+    type Parameters = BfvParameters;
+
+    fn try_from_with_parameters(value: &StateRound3Proto, parameters: &BfvParameters) -> Self {
+        StateRound3 {
+            party_id: value.party_id,
+            party_ids: value.party_ids.clone(),
+            mode: value.mode,
+            domain_size: value.domain_size,
+            cts_res: value
+                .cts_res
+                .iter()
+                .map(|c| convert(c, parameters))
+                .collect_vec(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use serde::de::DeserializeOwned;
+
+    use super::*;
+    use crate::{
+        crs::run_coin_toss, gen_keys, round1, round1_commit, round1_reveal, round2, round3_commit,
+        round3_reveal, MessageRound1Commit, MessageRound3Commit, PsiContext, PsiMode,
+        PsiParameters,
+    };
+
+    /// Checks that `value` survives a trip through its proto and JSON unchanged. The protos are
+    /// not printed on failure, since `PsiKeysProto` holds secret keys.
+    fn assert_round_trip<T, P>(value: &T, params: &BfvParameters)
+    where
+        P: TryFromWithParameters<Value = T, Parameters = BfvParameters>
+            + Serialize
+            + DeserializeOwned
+            + PartialEq,
+        T: TryFromWithParameters<Value = P, Parameters = BfvParameters>,
+    {
+        let proto: P = convert(value, params);
+        let json = serde_json::to_string(&proto).unwrap();
+        let proto_back: P = serde_json::from_str(&json).unwrap();
+        assert!(proto == proto_back);
+
+        let value_back: T = convert(&proto_back, params);
+        let proto_again: P = convert(&value_back, params);
+        assert!(proto == proto_again);
+    }
+
+    #[test]
+    fn round_trip_works() {
+        let psi_context = PsiContext::new(PsiParameters::small());
+        let params = psi_context.bfv_parameters();
+        let crs = run_coin_toss(&[0, 1]);
+        let bit_vector = vec![1; psi_context.psi_parameters().ring_size() + 1];
+
+        let (a_psi_keys, a_message_round1) = gen_keys(&psi_context, &crs, PsiMode::Intersection, 0);
+        let (b_psi_keys, b_message_round1) = gen_keys(&psi_context, &crs, PsiMode::Intersection, 1);
+        let (a_state_commit, _) =
+            round1_commit(&psi_context, &a_psi_keys, a_message_round1.clone());
+        let (_, a_message_reveal) = round1_reveal(
+            a_state_commit,
+            MessageRound1Commit {
+                commitment: [0; 32],
+            },
+            BTreeMap::new(),
+        )
+        .unwrap();
+        assert_round_trip::<_, MessageRound1RevealProto>(&a_message_reveal, params);
+        let (a_state_round2, a_message_round2) = round1(
+            &psi_context,
+            &a_psi_keys,
+            a_message_round1.clone(),
+            BTreeMap::from([(1, b_message_round1.clone())]),
+            &bit_vector,
+        )
+        .unwrap();
+        let (_, b_message_round2) = round1(
+            &psi_context,
+            &b_psi_keys,
+            b_message_round1.clone(),
+            BTreeMap::from([(0, a_message_round1.clone())]),
+            &bit_vector,
+        )
+        .unwrap();
+        assert_round_trip::<_, StateRound2Proto>(&a_state_round2, params);
+        assert_round_trip::<_, MessageRound2Proto>(&a_message_round2, params);
+
+        let (a_state_round3, a_message_round3) = round2(
+            &psi_context,
+            &a_psi_keys,
+            a_state_round2,
+            a_message_round2,
+            BTreeMap::from([(1, b_message_round2)]),
+        )
+        .unwrap();
+        assert_round_trip::<_, StateRound3Proto>(&a_state_round3, params);
+        assert_round_trip::<_, MessageRound3Proto>(&a_message_round3, params);
+        let (a_state_commit, _) = round3_commit(&psi_context, &a_psi_keys, a_message_round3);
+        let (_, a_message_reveal) = round3_reveal(
+            a_state_commit,
+            MessageRound3Commit {
+                commitment: [0; 32],
+            },
+            BTreeMap::new(),
+        )
+        .unwrap();
+        assert_round_trip::<_, MessageRound3RevealProto>(&a_message_reveal, params);
+
+        assert_round_trip::<_, PsiKeysProto>(&a_psi_keys, params);
+        assert_round_trip::<_, MessageRound1Proto>(&a_message_round1, params);
+        assert_round_trip::<_, MessageRound1Proto>(&b_message_round1, params);
+
+        let (_, c_message_round1) = gen_keys(&psi_context, &crs, PsiMode::Cardinality, 0);
+        assert_round_trip::<_, MessageRound1Proto>(&c_message_round1, params);
+    }
+}
@@ -0,0 +1,642 @@
+//! Relay that stores and forwards the messages of a session, so parties do not need to reach
+//! each other or be online at the same time.
+//!
+//! The relay speaks plain HTTP/1.1 and keeps every message in a file, addressed by session,
+//! sender, recipient and sequence number:
+//!
+//! ```text
+//! PUT /sessions/<session>/messages/<from>/<to>/<seq>   stores the body, 409 if it exists
+//! GET /sessions/<session>/messages/<from>/<to>/<seq>   returns the body, 404 until it exists
+//! ```
+//!
+//! Messages cannot be overwritten. The `n`-th message a party sends to a peer has sequence
+//! number `n`, starting at 0: [`run_session`](crate::run_session) sends the CRS commitment, the
+//! CRS reveal, the commitment to the key shares of `gen_keys`, the key shares and the messages of
+//! `round1` and `round2` as 0 to 5. The relay does not authenticate anyone, so it should only be
+//! reachable by the parties.
+//!
+//! Every response allows any origin, and `OPTIONS` answers the preflight of a browser, so the
+//! wasm build can talk to the relay from a web page.
+
+use std::{
+    collections::BTreeMap,
+    fs,
+    io::{self, BufRead, BufReader, Read, Write},
+    net::{TcpListener, TcpStream},
+    path::{Path, PathBuf},
+    sync::{Arc, Condvar, Mutex},
+    thread,
+    time::{Duration, Instant},
+};
+
+use rand::{thread_rng, RngCore};
+
+use crate::{PartyId, PsiError, Transport};
+
+/// Upper bound on the body of a request or response. Bodies are read as they arrive, so a
+/// client that only declares a large length does not make the relay allocate it.
+const MAX_BODY_LENGTH: usize = 1 << 28;
+const MAX_SESSION_LENGTH: usize = 64;
+
+/// Headers of every response, so browsers let web pages talk to the relay.
+const CORS_HEADERS: &str = "Access-Control-Allow-Origin: *\r\n\
+    Access-Control-Allow-Methods: GET, PUT, OPTIONS\r\n\
+    Access-Control-Allow-Headers: Content-Type\r\n\
+    Access-Control-Max-Age: 86400";
+
+/// Limits of a relay. The defaults suit a handful of concurrent sessions.
+#[derive(Clone, Debug)]
+pub struct RelayLimits {
+    /// Connections served at once. Further connections wait until one closes.
+    pub max_connections: usize,
+    /// How long a read from or write to a connection may block.
+    pub io_timeout: Duration,
+}
+
+impl Default for RelayLimits {
+    fn default() -> Self {
+        RelayLimits {
+            max_connections: 64,
+            io_timeout: Duration::from_secs(30),
+        }
+    }
+}
+
+/// Serves the relay on `listener`, storing messages below `directory`. Handles every
+/// connection on its own thread, at most `limits.max_connections` at once, and only returns
+/// if accepting fails.
+///
+/// A connection that fails, for example because the client goes silent for longer than
+/// `limits.io_timeout`, is closed and its error passed to `on_error`.
+pub fn serve<F>(
+    listener: TcpListener,
+    directory: PathBuf,
+    limits: RelayLimits,
+    on_error: F,
+) -> io::Result<()>
+where
+    F: Fn(io::Error) + Send + Sync + 'static,
+{
+    fs::create_dir_all(&directory)?;
+    let on_error = Arc::new(on_error);
+    let open_connections = Arc::new((Mutex::new(0), Condvar::new()));
+    loop {
+        {
+            let (count, released) = &*open_connections;
+            let mut count = released
+                .wait_while(count.lock().unwrap(), |count| {
+                    *count >= limits.max_connections
+                })
+                .unwrap();
+            *count += 1;
+        }
+
+        let connection = listener.accept().and_then(|(stream, _)| {
+            stream.set_read_timeout(Some(limits.io_timeout))?;
+            stream.set_write_timeout(Some(limits.io_timeout))?;
+            Ok(stream)
+        });
+        let stream = match connection {
+            Ok(stream) => stream,
+            Err(e) => {
+                release(&open_connections);
+                if e.kind() == io::ErrorKind::ConnectionAborted {
+                    continue;
+                }
+                return Err(e);
+            }
+        };
+
+        let directory = directory.clone();
+        let open_connections = open_connections.clone();
+        let on_error = on_error.clone();
+        thread::spawn(move || {
+            if let Err(e) = handle_connection(stream, &directory) {
+                on_error(e);
+            }
+            release(&open_connections);
+        });
+    }
+}
+
+fn release(open_connections: &(Mutex<usize>, Condvar)) {
+    let (count, released) = open_connections;
+    *count.lock().unwrap() -= 1;
+    released.notify_one();
+}
+
+fn handle_connection(mut stream: TcpStream, directory: &Path) -> io::Result<()> {
+    let (status, body) = match read_http_message(&mut BufReader::new(&mut stream)) {
+        Ok((request_line, body)) => handle_request(&request_line, body, directory),
+        Err(e) if e.kind() == io::ErrorKind::InvalidData => ("400 Bad Request", vec![]),
+        Err(e) => return Err(e),
+    };
+    write_http_message(
+        &mut stream,
+        &format!("HTTP/1.1 {status}\r\n{CORS_HEADERS}"),
+        &body,
+    )
+}
+
+fn handle_request(request_line: &str, body: Vec<u8>, directory: &Path) -> (&'static str, Vec<u8>) {
+    let mut parts = request_line.split(' ');
+    let (Some(method), Some(path)) = (parts.next(), parts.next()) else {
+        return ("400 Bad Request", vec![]);
+    };
+    let Some(path) = message_path(directory, path) else {
+        return ("404 Not Found", vec![]);
+    };
+
+    match method {
+        "OPTIONS" => ("204 No Content", vec![]),
+        "PUT" => match store(&path, &body) {
+            Ok(true) => ("201 Created", vec![]),
+            Ok(false) => ("409 Conflict", vec![]),
+            Err(_) => ("500 Internal Server Error", vec![]),
+        },
+        "GET" => match fs::read(&path) {
+            Ok(message) => ("200 OK", message),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => ("404 Not Found", vec![]),
+            Err(_) => ("500 Internal Server Error", vec![]),
+        },
+        _ => ("405 Method Not Allowed", vec![]),
+    }
+}
+
+/// File of the message at `/sessions/<session>/messages/<from>/<to>/<seq>`, if the path has
+/// that shape.
+fn message_path(directory: &Path, path: &str) -> Option<PathBuf> {
+    let parts = path.split('/').collect::<Vec<_>>();
+    let ["", "sessions", session, "messages", from, to, seq] = parts.as_slice() else {
+        return None;
+    };
+    let valid_session = !session.is_empty()
+        && session.len() <= MAX_SESSION_LENGTH
+        && session
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+    if !valid_session {
+        return None;
+    }
+    let from: PartyId = from.parse().ok()?;
+    let to: PartyId = to.parse().ok()?;
+    let seq: u64 = seq.parse().ok()?;
+
+    Some(directory.join(session).join(format!("{from}-{to}-{seq}")))
+}
+
+/// Writes `message` to `path` unless it exists. Returns whether it was written.
+fn store(path: &Path, message: &[u8]) -> io::Result<bool> {
+    let directory = path.parent().expect("messages live in a session directory");
+    fs::create_dir_all(directory)?;
+
+    // readers must never see a partially written message
+    let temporary = directory.join(format!(".{:016x}", thread_rng().next_u64()));
+    fs::write(&temporary, message)?;
+    let result = fs::hard_link(&temporary, path);
+    fs::remove_file(&temporary)?;
+    match result {
+        Ok(()) => Ok(true),
+        Err(e) if e.kind() == io::ErrorKind::AlreadyExists => Ok(false),
+        Err(e) => Err(e),
+    }
+}
+
+/// How long the client waits for the relay to accept or answer a request.
+const CLIENT_IO_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Client of a relay for one session.
+#[derive(Clone, Debug)]
+pub struct RelayClient {
+    address: String,
+    session: String,
+}
+
+impl RelayClient {
+    /// `address` is the `host:port` of the relay. All parties of a session must agree on the
+    /// `session` name beforehand.
+    pub fn new(address: &str, session: &str) -> RelayClient {
+        RelayClient {
+            address: address.to_string(),
+            session: session.to_string(),
+        }
+    }
+
+    /// Stores a message for `to`. Fails if the relay already holds a message at `seq`.
+    pub fn put(
+        &self,
+        from: PartyId,
+        to: PartyId,
+        seq: u64,
+        message: &[u8],
+    ) -> Result<(), PsiError> {
+        let (status, _) = self
+            .request("PUT", from, to, seq, message)
+            .map_err(|e| relay_error(to, e))?;
+        match status {
+            201 => Ok(()),
+            409 => Err(relay_error(to, format!("message {seq} was already sent"))),
+            status => Err(relay_error(to, format!("relay answered {status}"))),
+        }
+    }
+
+    /// Fetches the message `from` sent to `to` at `seq`, if the relay holds it yet.
+    pub fn get(&self, from: PartyId, to: PartyId, seq: u64) -> Result<Option<Vec<u8>>, PsiError> {
+        let (status, body) = self
+            .request("GET", from, to, seq, &[])
+            .map_err(|e| relay_error(from, e))?;
+        match status {
+            200 => Ok(Some(body)),
+            404 => Ok(None),
+            status => Err(relay_error(from, format!("relay answered {status}"))),
+        }
+    }
+
+    /// Polls the relay every `interval` until the message arrives. Fails once `timeout` has
+    /// passed without it.
+    pub fn wait(
+        &self,
+        from: PartyId,
+        to: PartyId,
+        seq: u64,
+        interval: Duration,
+        timeout: Duration,
+    ) -> Result<Vec<u8>, PsiError> {
+        self.poll(from, to, seq, interval, timeout)?.ok_or_else(|| {
+            relay_error(
+                from,
+                format!("message {seq} did not arrive within {timeout:?}"),
+            )
+        })
+    }
+
+    /// Like [`wait`](RelayClient::wait), returning `None` once `timeout` has passed.
+    fn poll(
+        &self,
+        from: PartyId,
+        to: PartyId,
+        seq: u64,
+        interval: Duration,
+        timeout: Duration,
+    ) -> Result<Option<Vec<u8>>, PsiError> {
+        let deadline = Instant::now() + timeout;
+        loop {
+            if let Some(message) = self.get(from, to, seq)? {
+                return Ok(Some(message));
+            }
+            let now = Instant::now();
+            if now >= deadline {
+                return Ok(None);
+            }
+            thread::sleep(interval.min(deadline - now));
+        }
+    }
+
+    fn request(
+        &self,
+        method: &str,
+        from: PartyId,
+        to: PartyId,
+        seq: u64,
+        body: &[u8],
+    ) -> io::Result<(u16, Vec<u8>)> {
+        let mut stream = TcpStream::connect(&self.address)?;
+        stream.set_read_timeout(Some(CLIENT_IO_TIMEOUT))?;
+        stream.set_write_timeout(Some(CLIENT_IO_TIMEOUT))?;
+        let request_line = format!(
+            "{method} /sessions/{}/messages/{from}/{to}/{seq} HTTP/1.1\r\nHost: {}",
+            self.session, self.address
+        );
+        write_http_message(&mut stream, &request_line, body)?;
+
+        let (status_line, body) = read_http_message(&mut BufReader::new(&mut stream))?;
+        let status = status_line
+            .split(' ')
+            .nth(1)
+            .and_then(|status| status.parse().ok())
+            .ok_or_else(|| invalid_data(format!("malformed status line {status_line}")))?;
+        Ok((status, body))
+    }
+}
+
+/// [`Transport`] through a relay. Parties never connect to each other, and a message can be
+/// fetched long after it was sent.
+pub struct RelayTransport {
+    client: RelayClient,
+    party_id: PartyId,
+    /// Sequence number of the next message to send to and receive from every peer.
+    next_sent: BTreeMap<PartyId, u64>,
+    next_received: BTreeMap<PartyId, u64>,
+    poll_interval: Duration,
+    /// How long [`Transport::recv`] waits for a peer before giving up.
+    timeout: Duration,
+}
+
+impl RelayTransport {
+    pub fn new(
+        client: RelayClient,
+        party_id: PartyId,
+        peers: &[PartyId],
+        poll_interval: Duration,
+        timeout: Duration,
+    ) -> RelayTransport {
+        let peers = peers
+            .iter()
+            .filter(|peer| **peer != party_id)
+            .map(|peer| (*peer, 0))
+            .collect::<BTreeMap<_, _>>();
+        RelayTransport {
+            client,
+            party_id,
+            next_sent: peers.clone(),
+            next_received: peers,
+            poll_interval,
+            timeout,
+        }
+    }
+}
+
+impl Transport for RelayTransport {
+    fn party_id(&self) -> PartyId {
+        self.party_id
+    }
+
+    fn peers(&self) -> Vec<PartyId> {
+        self.next_sent.keys().copied().collect()
+    }
+
+    fn send(&mut self, to: PartyId, frame: Vec<u8>) -> Result<(), PsiError> {
+        let seq = self
+            .next_sent
+            .get_mut(&to)
+            .ok_or_else(|| relay_error(to, "not a party of the session"))?;
+        self.client.put(self.party_id, to, *seq, &frame)?;
+        *seq += 1;
+        Ok(())
+    }
+
+    fn recv(&mut self, from: PartyId) -> Result<Vec<u8>, PsiError> {
+        let seq = self
+            .next_received
+            .get_mut(&from)
+            .ok_or_else(|| relay_error(from, "not a party of the session"))?;
+        let frame =
+            self.client
+                .wait(from, self.party_id, *seq, self.poll_interval, self.timeout)?;
+        *seq += 1;
+        Ok(frame)
+    }
+
+    fn recv_timeout(
+        &mut self,
+        from: PartyId,
+        timeout: Duration,
+    ) -> Result<Option<Vec<u8>>, PsiError> {
+        let seq = self
+            .next_received
+            .get_mut(&from)
+            .ok_or_else(|| relay_error(from, "not a party of the session"))?;
+        let frame = self
+            .client
+            .poll(from, self.party_id, *seq, self.poll_interval, timeout)?;
+        if frame.is_some() {
+            *seq += 1;
+        }
+        Ok(frame)
+    }
+}
+
+/// Reads the start line, the headers and the body of a request or response with a
+/// `Content-Length`.
+fn read_http_message(reader: &mut impl BufRead) -> io::Result<(String, Vec<u8>)> {
+    let mut start_line = String::new();
+    reader.read_line(&mut start_line)?;
+
+    let mut content_length = 0;
+    loop {
+        let mut header = String::new();
+        if reader.read_line(&mut header)? == 0 {
+            return Err(invalid_data("connection closed within the headers"));
+        }
+        let header = header.trim_end();
+        if header.is_empty() {
+            break;
+        }
+        if let Some((name, value)) = header.split_once(':') {
+            if name.eq_ignore_ascii_case("content-length") {
+                content_length = value
+                    .trim()
+                    .parse()
+                    .map_err(|_| invalid_data(format!("malformed header {header}")))?;
+            }
+        }
+    }
+    if content_length > MAX_BODY_LENGTH {
+        return Err(invalid_data(format!(
+            "body of {content_length} bytes exceeds the limit of {MAX_BODY_LENGTH}"
+        )));
+    }
+
+    // grow the buffer with the bytes that actually arrive instead of trusting the header
+    let mut body = Vec::new();
+    reader.take(content_length as u64).read_to_end(&mut body)?;
+    if body.len() != content_length {
+        return Err(io::Error::new(
+            io::ErrorKind::UnexpectedEof,
+            "connection closed within the body",
+        ));
+    }
+    Ok((start_line.trim_end().to_string(), body))
+}
+
+/// Writes `start_line`, which may carry further headers, and `body`, then closes the message.
+fn write_http_message(writer: &mut impl Write, start_line: &str, body: &[u8]) -> io::Result<()> {
+    write!(
+        writer,
+        "{start_line}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        body.len()
+    )?;
+    writer.write_all(body)?;
+    writer.flush()
+}
+
+fn invalid_data(reason: impl Into<String>) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, reason.into())
+}
+
+fn relay_error(party_id: PartyId, reason: impl ToString) -> PsiError {
+    PsiError::Transport {
+        party_id,
+        reason: format!("relay: {}", reason.to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use itertools::{izip, Itertools};
+    use rand::Rng;
+
+    use super::*;
+    use crate::{run_session, PsiContext, PsiMode, PsiOutput, PsiParameters};
+
+    /// Starts a relay on a free port and returns its address.
+    fn start_relay() -> String {
+        start_relay_with(RelayLimits::default(), |_| {})
+    }
+
+    fn start_relay_with<F>(limits: RelayLimits, on_error: F) -> String
+    where
+        F: Fn(io::Error) + Send + Sync + 'static,
+    {
+        let directory = std::env::temp_dir().join(format!(
+            "mp-psi-relay-{}-{}",
+            std::process::id(),
+            thread_rng().gen::<u64>()
+        ));
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        thread::spawn(move || serve(listener, directory, limits, on_error));
+        address
+    }
+
+    #[test]
+    fn relay_reports_failed_connections() {
+        let errors = Arc::new(Mutex::new(vec![]));
+        let address = {
+            let errors = errors.clone();
+            let limits = RelayLimits {
+                io_timeout: Duration::from_millis(100),
+                ..RelayLimits::default()
+            };
+            start_relay_with(limits, move |e| errors.lock().unwrap().push(e.kind()))
+        };
+
+        // a client that connects and never sends its request
+        let stream = TcpStream::connect(&address).unwrap();
+        thread::sleep(Duration::from_millis(500));
+        drop(stream);
+
+        let errors = errors.lock().unwrap();
+        assert_eq!(errors.len(), 1);
+        assert!(matches!(
+            errors[0],
+            io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+        ));
+    }
+
+    #[test]
+    fn relay_stores_messages_once() {
+        let client = RelayClient::new(&start_relay(), "session-1");
+
+        assert_eq!(client.get(0, 1, 0), Ok(None));
+        client.put(0, 1, 0, b"hello").unwrap();
+        assert_eq!(client.get(0, 1, 0), Ok(Some(b"hello".to_vec())));
+        assert!(matches!(
+            client.put(0, 1, 0, b"changed"),
+            Err(PsiError::Transport { party_id: 1, .. })
+        ));
+        assert_eq!(
+            RelayClient::new(&client.address, "session-2").get(0, 1, 0),
+            Ok(None)
+        );
+    }
+
+    #[test]
+    fn relay_answers_preflight() {
+        let mut stream = TcpStream::connect(start_relay()).unwrap();
+        write_http_message(
+            &mut stream,
+            "OPTIONS /sessions/session/messages/0/1/0 HTTP/1.1\r\nOrigin: http://localhost",
+            &[],
+        )
+        .unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+
+        assert!(response.starts_with("HTTP/1.1 204 No Content\r\n"));
+        assert!(response.contains("Access-Control-Allow-Origin: *\r\n"));
+        assert!(response.contains("Access-Control-Allow-Methods: GET, PUT, OPTIONS\r\n"));
+    }
+
+    #[test]
+    fn relay_rejects_oversized_body() {
+        let mut stream = TcpStream::connect(start_relay()).unwrap();
+        write!(
+            stream,
+            "PUT /sessions/session/messages/0/1/0 HTTP/1.1\r\nContent-Length: {}\r\n\r\n",
+            MAX_BODY_LENGTH + 1
+        )
+        .unwrap();
+        let (status_line, _) = read_http_message(&mut BufReader::new(&mut stream)).unwrap();
+
+        assert_eq!(status_line, "HTTP/1.1 400 Bad Request");
+    }
+
+    #[test]
+    fn wait_gives_up_after_timeout() {
+        let client = RelayClient::new(&start_relay(), "session");
+
+        assert!(matches!(
+            client.wait(
+                0,
+                1,
+                0,
+                Duration::from_millis(10),
+                Duration::from_millis(50)
+            ),
+            Err(PsiError::Transport { party_id: 0, .. })
+        ));
+    }
+
+    #[test]
+    fn session_over_relay_works() {
+        let address = start_relay();
+        let psi_parameters = PsiParameters::small();
+        let mut rng = thread_rng();
+        let bit_vectors = (0..2)
+            .map(|_| {
+                (0..psi_parameters.ring_size())
+                    .map(|_| rng.gen_range(0..2))
+                    .collect_vec()
+            })
+            .collect_vec();
+
+        // the second party only starts once the first one has sent its commitment
+        let handles = bit_vectors
+            .iter()
+            .enumerate()
+            .map(|(party_id, bit_vector)| {
+                let client = RelayClient::new(&address, "session");
+                let psi_parameters = psi_parameters.clone();
+                let bit_vector = bit_vector.clone();
+                let handle = thread::spawn(move || {
+                    let mut transport = RelayTransport::new(
+                        client,
+                        party_id as PartyId,
+                        &[0, 1],
+                        Duration::from_millis(10),
+                        Duration::from_secs(600),
+                    );
+                    run_session(
+                        &mut transport,
+                        &PsiContext::new(psi_parameters),
+                        PsiMode::Intersection,
+                        &bit_vector,
+                    )
+                });
+                thread::sleep(Duration::from_millis(200));
+                handle
+            })
+            .collect_vec();
+
+        let expected_psi_output = izip!(&bit_vectors[0], &bit_vectors[1])
+            .map(|(a, b)| a * b)
+            .collect_vec();
+        handles.into_iter().for_each(|handle| {
+            assert_eq!(
+                handle.join().unwrap(),
+                Ok(PsiOutput::Intersection(expected_psi_output.clone()))
+            );
+        });
+    }
+}
@@ -0,0 +1,505 @@
+use std::{
+    collections::BTreeMap,
+    time::{Duration, Instant},
+};
+
+use bfv::BfvParameters;
+use serde::{de::DeserializeOwned, Serialize};
+use traits::TryFromWithParameters;
+
+use crate::{
+    convert, convert_peer_messages, crs_commit, crs_derive, crs_reveal, gen_keys,
+    proto::{
+        MessageRound1RevealProto, MessageRound2Proto, MessageRound3Proto, MessageRound3RevealProto,
+    },
+    round1, round1_commit, round1_open, round1_reveal, round2, round3, round3_cardinality,
+    round3_commit, round3_open, round3_reveal, MessageRound1Commit, MessageRound3Commit,
+    MessageRound3Reveal, PartyId, PsiContext, PsiError, PsiMode, PsiOutput, SharePolys, Transport,
+};
+
+/// Runs a whole session over `transport`: the coin toss of the CRS, `gen_keys` and the three
+/// rounds. Every party of the session must call it with the same parameters and mode.
+///
+/// The key shares of `gen_keys` are exchanged with the commit-then-reveal of [`round1_commit`],
+/// so no party can choose its shares after seeing those of the others.
+///
+/// Messages are encoded with bincode, one frame per message and peer.
+pub fn run_session<T: Transport>(
+    transport: &mut T,
+    psi_context: &PsiContext,
+    mode: PsiMode,
+    bit_vector: &[u32],
+) -> Result<PsiOutput, PsiError> {
+    run_session_with(transport, psi_context, mode, bit_vector, None)
+}
+
+/// Like [`run_session`], exchanging the decryption shares with the commit-then-reveal of
+/// [`round3_commit`]. A peer that drops out after the commitments, or whose shares do not
+/// arrive within `reveal_timeout` of sending our own, fails the session with
+/// [`PsiError::MissingReveal`] naming it.
+///
+/// All parties of the session must call this function rather than [`run_session`].
+pub fn run_fair_session<T: Transport>(
+    transport: &mut T,
+    psi_context: &PsiContext,
+    mode: PsiMode,
+    bit_vector: &[u32],
+    reveal_timeout: Duration,
+) -> Result<PsiOutput, PsiError> {
+    run_session_with(
+        transport,
+        psi_context,
+        mode,
+        bit_vector,
+        Some(reveal_timeout),
+    )
+}
+
+/// Runs the session, in the fair mode if `reveal_timeout` is set.
+fn run_session_with<T: Transport>(
+    transport: &mut T,
+    psi_context: &PsiContext,
+    mode: PsiMode,
+    bit_vector: &[u32],
+    reveal_timeout: Option<Duration>,
+) -> Result<PsiOutput, PsiError> {
+    let party_id = transport.party_id();
+
+    // coin toss
+    let (state, message) = crs_commit(party_id);
+    let other_messages = exchange(transport, &message)?;
+    let (state, message) = crs_reveal(state, message, other_messages)?;
+    let other_messages = exchange(transport, &message)?;
+    let crs = crs_derive(state, message, other_messages)?;
+
+    let (psi_keys, message) = gen_keys(psi_context, &crs, mode, party_id);
+
+    let (state, message) = round1_commit(psi_context, &psi_keys, message);
+    let other_messages: BTreeMap<_, MessageRound1Commit> = exchange(transport, &message)?;
+    let (state, message) = round1_reveal(state, message, other_messages)?;
+    let other_messages =
+        exchange_converted::<_, _, MessageRound1RevealProto>(transport, psi_context, &message)?;
+    let (message, other_messages) = round1_open(psi_context, state, message, other_messages)?;
+    let (state, message) = round1(psi_context, &psi_keys, message, other_messages, bit_vector)?;
+
+    let other_messages =
+        exchange_converted::<_, _, MessageRound2Proto>(transport, psi_context, &message)?;
+    let (state, message) = round2(psi_context, &psi_keys, state, message, other_messages)?;
+
+    let (message, other_messages) = if let Some(reveal_timeout) = reveal_timeout {
+        let (state_commit, message) = round3_commit(psi_context, &psi_keys, message);
+        let other_messages: BTreeMap<_, MessageRound3Commit> = exchange(transport, &message)?;
+        let (state_reveal, message) = round3_reveal(state_commit, message, other_messages)?;
+        let other_messages = exchange_reveals(transport, psi_context, &message, reveal_timeout)?;
+        round3_open(psi_context, state_reveal, message, other_messages)?
+    } else {
+        let other_messages =
+            exchange_converted::<_, _, MessageRound3Proto>(transport, psi_context, &message)?;
+        (message, other_messages)
+    };
+    match mode {
+        PsiMode::Cardinality => Ok(PsiOutput::Cardinality(round3_cardinality(
+            psi_context,
+            state,
+            message,
+            other_messages,
+        )?)),
+        PsiMode::Intersection | PsiMode::Threshold { .. } => Ok(PsiOutput::Intersection(round3(
+            psi_context,
+            state,
+            message,
+            other_messages,
+        )?)),
+    }
+}
+
+/// Sends `message` to every peer and returns the message every peer sent in turn.
+fn exchange<T, M>(transport: &mut T, message: &M) -> Result<BTreeMap<PartyId, M>, PsiError>
+where
+    T: Transport,
+    M: Serialize + DeserializeOwned,
+{
+    send_to_peers(transport, message)?
+        .into_iter()
+        .map(|peer| Ok((peer, decode(peer, &transport.recv(peer)?)?)))
+        .collect()
+}
+
+/// Sends `message` to every peer and returns the peers.
+fn send_to_peers<T: Transport, M: Serialize>(
+    transport: &mut T,
+    message: &M,
+) -> Result<Vec<PartyId>, PsiError> {
+    let frame =
+        bincode::serialize(message).map_err(|e| PsiError::Serialization(format!("frame: {e}")))?;
+    let peers = transport.peers();
+    for peer in peers.iter() {
+        transport.send(*peer, frame.clone())?;
+    }
+    Ok(peers)
+}
+
+fn decode<M: DeserializeOwned>(peer: PartyId, frame: &[u8]) -> Result<M, PsiError> {
+    bincode::deserialize(frame)
+        .map_err(|e| PsiError::Deserialization(format!("message from party {peer}: {e}")))
+}
+
+/// Like [`exchange_converted`] for the reveals of the fair mode. A peer whose reveal does not
+/// arrive within `timeout`, or whose connection fails while we wait for it, holds our shares
+/// without having revealed its own and is named in [`PsiError::MissingReveal`]. Failing to send
+/// our own reveal is a transport error.
+fn exchange_reveals<T: Transport>(
+    transport: &mut T,
+    psi_context: &PsiContext,
+    message: &MessageRound3Reveal,
+    timeout: Duration,
+) -> Result<BTreeMap<PartyId, MessageRound3Reveal>, PsiError> {
+    let message: MessageRound3RevealProto = convert(message, psi_context.bfv_parameters());
+    let peers = send_to_peers(transport, &message)?;
+
+    let deadline = Instant::now() + timeout;
+    let other_messages = peers
+        .into_iter()
+        .map(|peer| {
+            let frame = transport
+                .recv_timeout(peer, deadline.saturating_duration_since(Instant::now()))
+                .map_err(|e| match e {
+                    PsiError::Transport { party_id, .. } if party_id == peer => {
+                        PsiError::MissingReveal { party_id }
+                    }
+                    e => e,
+                })?
+                .ok_or(PsiError::MissingReveal { party_id: peer })?;
+            Ok((peer, decode(peer, &frame)?))
+        })
+        .collect::<Result<BTreeMap<_, _>, PsiError>>()?;
+    convert_peer_messages(psi_context, &message, &other_messages)
+}
+
+/// Like [`exchange`], sending `message` in its serializable form `P` and checking the messages
+/// of the peers before they are converted back.
+fn exchange_converted<T, M, P>(
+    transport: &mut T,
+    psi_context: &PsiContext,
+    message: &M,
+) -> Result<BTreeMap<PartyId, M>, PsiError>
+where
+    T: Transport,
+    M: TryFromWithParameters<Value = P, Parameters = BfvParameters>,
+    P: TryFromWithParameters<Value = M, Parameters = BfvParameters>
+        + SharePolys
+        + Serialize
+        + DeserializeOwned,
+{
+    let message: P = convert(message, psi_context.bfv_parameters());
+    let other_messages = exchange(transport, &message)?;
+    convert_peer_messages(psi_context, &message, &other_messages)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{net::TcpListener, thread};
+
+    use itertools::{izip, Itertools};
+    use rand::{thread_rng, Rng};
+
+    use super::*;
+    use crate::{ChannelTransport, PsiParameters, StreamTransport};
+
+    fn random_bit_vectors(party_count: usize, size: usize) -> Vec<Vec<u32>> {
+        let mut rng = thread_rng();
+        (0..party_count)
+            .map(|_| (0..size).map(|_| rng.gen_range(0..2)).collect_vec())
+            .collect_vec()
+    }
+
+    /// Runs a session with one thread per transport and checks every party's output.
+    fn assert_session_works<T: Transport + Send + 'static>(
+        transports: Vec<Box<dyn FnOnce() -> T + Send>>,
+        reveal_timeout: Option<Duration>,
+    ) {
+        let psi_parameters = PsiParameters::small();
+        let bit_vectors = random_bit_vectors(transports.len(), psi_parameters.ring_size());
+
+        let handles = izip!(transports, bit_vectors.clone())
+            .map(|(transport, bit_vector)| {
+                let psi_parameters = psi_parameters.clone();
+                thread::spawn(move || {
+                    run_session_with(
+                        &mut transport(),
+                        &PsiContext::new(psi_parameters),
+                        PsiMode::Intersection,
+                        &bit_vector,
+                        reveal_timeout,
+                    )
+                })
+            })
+            .collect_vec();
+
+        let expected_psi_output = izip!(&bit_vectors[0], &bit_vectors[1])
+            .map(|(a, b)| a * b)
+            .collect_vec();
+        handles.into_iter().for_each(|handle| {
+            assert_eq!(
+                handle.join().unwrap(),
+                Ok(PsiOutput::Intersection(expected_psi_output.clone()))
+            );
+        });
+    }
+
+    #[test]
+    fn session_over_channels_works() {
+        assert_session_works(
+            ChannelTransport::mesh(&[0, 1])
+                .into_iter()
+                .map(|transport| Box::new(move || transport) as Box<dyn FnOnce() -> _ + Send>)
+                .collect(),
+            None,
+        );
+    }
+
+    #[test]
+    fn fair_session_over_channels_works() {
+        assert_session_works(
+            ChannelTransport::mesh(&[0, 1])
+                .into_iter()
+                .map(|transport| Box::new(move || transport) as Box<dyn FnOnce() -> _ + Send>)
+                .collect(),
+            Some(Duration::from_secs(60)),
+        );
+    }
+
+    /// Silently drops every frame after the first `frames`, like a party that keeps its
+    /// decryption shares once it holds those of its peers.
+    struct WithholdingTransport {
+        inner: ChannelTransport,
+        frames: usize,
+    }
+
+    impl Transport for WithholdingTransport {
+        fn party_id(&self) -> PartyId {
+            self.inner.party_id()
+        }
+
+        fn peers(&self) -> Vec<PartyId> {
+            self.inner.peers()
+        }
+
+        fn send(&mut self, to: PartyId, frame: Vec<u8>) -> Result<(), PsiError> {
+            if self.frames == 0 {
+                return Ok(());
+            }
+            self.frames -= 1;
+            self.inner.send(to, frame)
+        }
+
+        fn recv(&mut self, from: PartyId) -> Result<Vec<u8>, PsiError> {
+            self.inner.recv(from)
+        }
+    }
+
+    /// Runs a fair session between party 0 and a party 1 that withholds its reveal, and
+    /// returns the results of both. Party 1 drops its transport once it is done unless
+    /// `keep_connection` is set.
+    fn run_withheld_reveal(
+        reveal_timeout: Duration,
+        keep_connection: bool,
+    ) -> (Result<PsiOutput, PsiError>, Result<PsiOutput, PsiError>) {
+        let psi_parameters = PsiParameters::small();
+        let bit_vector = vec![1; psi_parameters.ring_size()];
+        let mut transports = ChannelTransport::mesh(&[0, 1]);
+        // the coin toss, the key shares and their commitment, rounds 1 and 2 and the commitment
+        // to the decryption shares take six frames
+        let mut b_transport = WithholdingTransport {
+            inner: transports.pop().unwrap(),
+            frames: 6,
+        };
+        let mut a_transport = transports.pop().unwrap();
+
+        let handle = {
+            let psi_parameters = psi_parameters.clone();
+            let bit_vector = bit_vector.clone();
+            thread::spawn(move || {
+                let result = run_fair_session(
+                    &mut b_transport,
+                    &PsiContext::new(psi_parameters),
+                    PsiMode::Intersection,
+                    &bit_vector,
+                    Duration::from_secs(60),
+                );
+                (result, keep_connection.then_some(b_transport))
+            })
+        };
+        let a_result = run_fair_session(
+            &mut a_transport,
+            &PsiContext::new(psi_parameters),
+            PsiMode::Intersection,
+            &bit_vector,
+            reveal_timeout,
+        );
+        let (b_result, _) = handle.join().unwrap();
+        (a_result, b_result)
+    }
+
+    #[test]
+    fn fair_session_detects_abort_after_commitments() {
+        let (a_result, b_result) = run_withheld_reveal(Duration::from_secs(60), false);
+
+        assert_eq!(a_result, Err(PsiError::MissingReveal { party_id: 1 }));
+        assert!(b_result.is_ok());
+    }
+
+    #[test]
+    fn fair_session_detects_late_reveal() {
+        let (a_result, b_result) = run_withheld_reveal(Duration::from_millis(500), true);
+
+        assert_eq!(a_result, Err(PsiError::MissingReveal { party_id: 1 }));
+        assert!(b_result.is_ok());
+    }
+
+    /// Passes the frame with index `frame` through `tamper` before sending it.
+    struct TamperingTransport {
+        inner: ChannelTransport,
+        frame: usize,
+        tamper: fn(Vec<u8>) -> Vec<u8>,
+        sent: usize,
+    }
+
+    impl Transport for TamperingTransport {
+        fn party_id(&self) -> PartyId {
+            self.inner.party_id()
+        }
+
+        fn peers(&self) -> Vec<PartyId> {
+            self.inner.peers()
+        }
+
+        fn send(&mut self, to: PartyId, frame: Vec<u8>) -> Result<(), PsiError> {
+            let frame = if self.sent == self.frame {
+                (self.tamper)(frame)
+            } else {
+                frame
+            };
+            self.sent += 1;
+            self.inner.send(to, frame)
+        }
+
+        fn recv(&mut self, from: PartyId) -> Result<Vec<u8>, PsiError> {
+            self.inner.recv(from)
+        }
+    }
+
+    #[test]
+    fn session_rejects_truncated_share() {
+        let psi_parameters = PsiParameters::small();
+        let bit_vector = vec![1; psi_parameters.ring_size()];
+        let mut transports = ChannelTransport::mesh(&[0, 1]);
+        // the key shares follow the two frames of the coin toss and their commitment
+        let mut b_transport = TamperingTransport {
+            inner: transports.pop().unwrap(),
+            frame: 3,
+            tamper: |frame| {
+                let mut message: MessageRound1RevealProto = bincode::deserialize(&frame).unwrap();
+                message
+                    .message
+                    .share_pk
+                    .share
+                    .as_mut()
+                    .unwrap()
+                    .coefficients[0]
+                    .pop();
+                bincode::serialize(&message).unwrap()
+            },
+            sent: 0,
+        };
+        let mut a_transport = transports.pop().unwrap();
+
+        let handle = {
+            let psi_parameters = psi_parameters.clone();
+            let bit_vector = bit_vector.clone();
+            thread::spawn(move || {
+                run_session(
+                    &mut b_transport,
+                    &PsiContext::new(psi_parameters),
+                    PsiMode::Intersection,
+                    &bit_vector,
+                )
+            })
+        };
+        let result = run_session(
+            &mut a_transport,
+            &PsiContext::new(psi_parameters),
+            PsiMode::Intersection,
+            &bit_vector,
+        );
+        drop(a_transport);
+
+        assert!(matches!(
+            result,
+            Err(PsiError::MalformedShare { party_id: 1, .. })
+        ));
+        assert!(handle.join().unwrap().is_err());
+    }
+
+    #[test]
+    fn session_over_tcp_works() {
+        let listeners = (0..2)
+            .map(|_| TcpListener::bind("127.0.0.1:0").unwrap())
+            .collect_vec();
+        let addresses = listeners
+            .iter()
+            .enumerate()
+            .map(|(party_id, l)| (party_id as PartyId, l.local_addr().unwrap()))
+            .collect::<BTreeMap<_, _>>();
+
+        assert_session_works(
+            listeners
+                .into_iter()
+                .enumerate()
+                .map(|(party_id, listener)| {
+                    let addresses = addresses.clone();
+                    Box::new(move || {
+                        StreamTransport::tcp(party_id as PartyId, listener, &addresses).unwrap()
+                    }) as Box<dyn FnOnce() -> _ + Send>
+                })
+                .collect(),
+            None,
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn session_over_unix_sockets_works() {
+        use std::os::unix::net::UnixListener;
+
+        let directory = std::env::temp_dir().join(format!(
+            "mp-psi-{}-{}",
+            std::process::id(),
+            thread_rng().gen::<u64>()
+        ));
+        std::fs::create_dir(&directory).unwrap();
+        let paths = (0..2)
+            .map(|party_id| (party_id, directory.join(format!("party-{party_id}.sock"))))
+            .collect::<BTreeMap<PartyId, _>>();
+        let listeners = paths
+            .values()
+            .map(|path| UnixListener::bind(path).unwrap())
+            .collect_vec();
+
+        assert_session_works(
+            listeners
+                .into_iter()
+                .enumerate()
+                .map(|(party_id, listener)| {
+                    let paths = paths.clone();
+                    Box::new(move || {
+                        StreamTransport::unix(party_id as PartyId, listener, &paths).unwrap()
+                    }) as Box<dyn FnOnce() -> _ + Send>
+                })
+                .collect(),
+            None,
+        );
+
+        std::fs::remove_dir_all(directory).unwrap();
+    }
+}
@@ -0,0 +1,257 @@
+//! Known-answer test of a seeded two party session.
+//!
+//! `test_vectors/session.json` holds the inputs and RNG seeds of the session, every message as
+//! it goes over the wire and the output. A changed message means that the wire format or the
+//! sampling of a round changed, and that the release cannot run sessions with earlier ones. The
+//! messages are stored in full, so that other implementations can check their encoding against
+//! them.
+//!
+//! After an intended change, regenerate the file with
+//! `cargo test --release write_test_vectors -- --ignored` and commit it.
+
+use std::{collections::BTreeMap, fs, path::PathBuf};
+
+use itertools::{izip, Itertools};
+use rand::SeedableRng;
+use rand_chacha::ChaCha20Rng;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    convert, crs_commit_with_rng, crs_derive, crs_reveal, gen_keys_with_rng,
+    proto::{MessageRound1Proto, MessageRound2Proto, MessageRound3Proto},
+    round1_with_rng, round2_with_rng, round3,
+    tests::others,
+    PartyId, PsiContext, PsiMode, PsiParameters,
+};
+
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+struct TestVectors {
+    parameters: PsiParameters,
+    mode: PsiMode,
+    /// Seed of the RNG of every party.
+    seeds: Vec<u64>,
+    /// Slots set in the bit vector of every party.
+    inputs: Vec<Vec<usize>>,
+    /// Hex of the bincode encoding of every message, in party order.
+    messages: BTreeMap<String, Vec<String>>,
+    /// Slots in the intersection, as decrypted by every party.
+    output: Vec<Vec<usize>>,
+}
+
+fn path() -> PathBuf {
+    PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("test_vectors/session.json")
+}
+
+fn encode<T: Serialize>(message: &T) -> String {
+    bincode::serialize(message)
+        .unwrap()
+        .iter()
+        .map(|b| format!("{b:02x}"))
+        .collect()
+}
+
+/// Runs a session in which every party samples from an RNG seeded with its entry of `seeds`.
+fn run(
+    parameters: PsiParameters,
+    mode: PsiMode,
+    seeds: Vec<u64>,
+    inputs: Vec<Vec<usize>>,
+) -> TestVectors {
+    let psi_context = PsiContext::new(parameters.clone());
+    let params = psi_context.bfv_parameters();
+    let party_ids = (0..seeds.len() as PartyId).collect_vec();
+    let mut rngs = seeds
+        .iter()
+        .map(|seed| ChaCha20Rng::seed_from_u64(*seed))
+        .collect_vec();
+    let bit_vectors = inputs
+        .iter()
+        .map(|slots| {
+            let mut bit_vector = vec![0; parameters.ring_size()];
+            slots.iter().for_each(|slot| bit_vector[*slot] = 1);
+            bit_vector
+        })
+        .collect_vec();
+    let mut messages = BTreeMap::new();
+
+    // coin toss
+    let (states, commits): (Vec<_>, BTreeMap<_, _>) = izip!(&party_ids, &mut rngs)
+        .map(|(party_id, rng)| {
+            let (state, message) = crs_commit_with_rng(*party_id, rng);
+            (state, (*party_id, message))
+        })
+        .unzip();
+    messages.insert(
+        "MessageCrsCommit",
+        commits.values().map(encode).collect_vec(),
+    );
+    let (states, reveals): (Vec<_>, BTreeMap<_, _>) = izip!(states, &party_ids)
+        .map(|(state, party_id)| {
+            let (state, message) = crs_reveal(
+                state,
+                commits[party_id].clone(),
+                others(&commits, *party_id),
+            )
+            .unwrap();
+            (state, (*party_id, message))
+        })
+        .unzip();
+    messages.insert(
+        "MessageCrsReveal",
+        reveals.values().map(encode).collect_vec(),
+    );
+    let crs = izip!(states, &party_ids)
+        .map(|(state, party_id)| {
+            crs_derive(
+                state,
+                reveals[party_id].clone(),
+                others(&reveals, *party_id),
+            )
+            .unwrap()
+        })
+        .collect_vec();
+
+    let (keys, messages_round1): (Vec<_>, BTreeMap<_, _>) = izip!(&party_ids, &crs, &mut rngs)
+        .map(|(party_id, crs, rng)| {
+            let (psi_keys, message) = gen_keys_with_rng(&psi_context, crs, mode, *party_id, rng);
+            (psi_keys, (*party_id, message))
+        })
+        .unzip();
+    messages.insert(
+        "MessageRound1",
+        messages_round1
+            .values()
+            .map(|m| encode(&convert::<_, MessageRound1Proto>(m, params)))
+            .collect_vec(),
+    );
+
+    let (states, messages_round2): (Vec<_>, BTreeMap<_, _>) = izip!(&keys, &bit_vectors, &mut rngs)
+        .map(|(psi_keys, bit_vector, rng)| {
+            let (state, message) = round1_with_rng(
+                &psi_context,
+                psi_keys,
+                messages_round1[&psi_keys.party_id].clone(),
+                others(&messages_round1, psi_keys.party_id),
+                bit_vector,
+                rng,
+            )
+            .unwrap();
+            (state, (psi_keys.party_id, message))
+        })
+        .unzip();
+    messages.insert(
+        "MessageRound2",
+        messages_round2
+            .values()
+            .map(|m| encode(&convert::<_, MessageRound2Proto>(m, params)))
+            .collect_vec(),
+    );
+
+    let (states, messages_round3): (Vec<_>, BTreeMap<_, _>) = izip!(&keys, states, &mut rngs)
+        .map(|(psi_keys, state, rng)| {
+            let (state, message) = round2_with_rng(
+                &psi_context,
+                psi_keys,
+                state,
+                messages_round2[&psi_keys.party_id].clone(),
+                others(&messages_round2, psi_keys.party_id),
+                rng,
+            )
+            .unwrap();
+            (state, (psi_keys.party_id, message))
+        })
+        .unzip();
+    messages.insert(
+        "MessageRound3",
+        messages_round3
+            .values()
+            .map(|m| encode(&convert::<_, MessageRound3Proto>(m, params)))
+            .collect_vec(),
+    );
+
+    let output = izip!(&party_ids, states)
+        .map(|(party_id, state)| {
+            round3(
+                &psi_context,
+                state,
+                messages_round3[party_id].clone(),
+                others(&messages_round3, *party_id),
+            )
+            .unwrap()
+            .into_iter()
+            .positions(|b| b == 1)
+            .collect_vec()
+        })
+        .collect_vec();
+
+    TestVectors {
+        parameters,
+        mode,
+        seeds,
+        inputs,
+        messages: messages
+            .into_iter()
+            .map(|(name, encodings)| (name.to_string(), encodings))
+            .collect(),
+        output,
+    }
+}
+
+fn default_session() -> TestVectors {
+    run(
+        PsiParameters::small(),
+        PsiMode::Intersection,
+        vec![1, 2],
+        vec![(0..16).collect(), (8..24).collect()],
+    )
+}
+
+#[test]
+fn seeded_session_is_reproducible() {
+    let session = default_session();
+
+    assert_eq!(session, default_session());
+    assert_eq!(session.output, vec![(8..16).collect_vec(); 2]);
+    let reseeded = run(
+        session.parameters.clone(),
+        session.mode,
+        vec![1, 3],
+        session.inputs.clone(),
+    );
+    assert_eq!(
+        reseeded.messages["MessageCrsCommit"][0],
+        session.messages["MessageCrsCommit"][0]
+    );
+    assert_ne!(
+        reseeded.messages["MessageCrsCommit"][1],
+        session.messages["MessageCrsCommit"][1]
+    );
+}
+
+#[test]
+fn seeded_session_matches_test_vectors() {
+    let contents = fs::read_to_string(path())
+        .unwrap_or_else(|e| panic!("cannot read {}: {e}", path().display()));
+    let expected: TestVectors = serde_json::from_str(&contents).unwrap();
+
+    let session = run(
+        expected.parameters.clone(),
+        expected.mode,
+        expected.seeds.clone(),
+        expected.inputs.clone(),
+    );
+
+    assert_eq!(session, expected);
+}
+
+/// Overwrites the test vectors with the current session. Only runs when asked for by name.
+#[test]
+#[ignore]
+fn write_test_vectors() {
+    fs::create_dir_all(path().parent().unwrap()).unwrap();
+    fs::write(
+        path(),
+        serde_json::to_string_pretty(&default_session()).unwrap() + "\n",
+    )
+    .unwrap();
+}
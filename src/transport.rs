@@ -0,0 +1,470 @@
+//! Delivery of the round messages between the parties of a session.
+//!
+//! A [`Transport`] sends and receives opaque frames addressed by party id, and
+//! [`run_session`](crate::run_session) drives a whole session over it. Frames between two
+//! parties must arrive in the order they were sent.
+
+use std::{
+    collections::BTreeMap,
+    io::{self, Read, Write},
+    net::{SocketAddr, TcpListener, TcpStream},
+    sync::mpsc::{channel, Receiver, RecvTimeoutError, Sender},
+    thread,
+    time::Duration,
+};
+
+use itertools::Itertools;
+
+use crate::{PartyId, PsiError};
+
+/// Upper bound on the length of a frame read from a stream. Frames are read as their bytes
+/// arrive, so a peer that only declares a large length does not make us allocate it.
+const MAX_FRAME_LENGTH: usize = 1 << 28;
+
+/// How often and how long to wait for a peer that is not listening yet.
+const CONNECT_ATTEMPTS: usize = 100;
+const CONNECT_DELAY: Duration = Duration::from_millis(100);
+
+pub trait Transport {
+    /// Id of the local party.
+    fn party_id(&self) -> PartyId;
+
+    /// Ids of all other parties of the session, in ascending order.
+    fn peers(&self) -> Vec<PartyId>;
+
+    fn send(&mut self, to: PartyId, frame: Vec<u8>) -> Result<(), PsiError>;
+
+    /// Blocks until the next frame from `from` arrives.
+    fn recv(&mut self, from: PartyId) -> Result<Vec<u8>, PsiError>;
+
+    /// Like [`recv`](Transport::recv), returning `None` if no frame arrived within `timeout`.
+    /// Transports that cannot time out block like `recv`.
+    fn recv_timeout(
+        &mut self,
+        from: PartyId,
+        timeout: Duration,
+    ) -> Result<Option<Vec<u8>>, PsiError> {
+        let _ = timeout;
+        self.recv(from).map(Some)
+    }
+}
+
+/// Transport between parties running in the same process, for example on different threads.
+pub struct ChannelTransport {
+    party_id: PartyId,
+    senders: BTreeMap<PartyId, Sender<Vec<u8>>>,
+    receivers: BTreeMap<PartyId, Receiver<Vec<u8>>>,
+}
+
+impl ChannelTransport {
+    /// Connects every pair of `party_ids` and returns the transport of each party, in the order
+    /// of `party_ids`.
+    pub fn mesh(party_ids: &[PartyId]) -> Vec<ChannelTransport> {
+        let mut transports = party_ids
+            .iter()
+            .map(|party_id| ChannelTransport {
+                party_id: *party_id,
+                senders: BTreeMap::new(),
+                receivers: BTreeMap::new(),
+            })
+            .collect_vec();
+
+        for from in 0..party_ids.len() {
+            for to in 0..party_ids.len() {
+                if from != to {
+                    let (sender, receiver) = channel();
+                    transports[from].senders.insert(party_ids[to], sender);
+                    transports[to].receivers.insert(party_ids[from], receiver);
+                }
+            }
+        }
+
+        transports
+    }
+}
+
+impl Transport for ChannelTransport {
+    fn party_id(&self) -> PartyId {
+        self.party_id
+    }
+
+    fn peers(&self) -> Vec<PartyId> {
+        self.senders.keys().copied().collect()
+    }
+
+    fn send(&mut self, to: PartyId, frame: Vec<u8>) -> Result<(), PsiError> {
+        self.senders
+            .get(&to)
+            .ok_or_else(|| unknown_party(to))?
+            .send(frame)
+            .map_err(|_| closed(to))
+    }
+
+    fn recv(&mut self, from: PartyId) -> Result<Vec<u8>, PsiError> {
+        self.receivers
+            .get(&from)
+            .ok_or_else(|| unknown_party(from))?
+            .recv()
+            .map_err(|_| closed(from))
+    }
+
+    fn recv_timeout(
+        &mut self,
+        from: PartyId,
+        timeout: Duration,
+    ) -> Result<Option<Vec<u8>>, PsiError> {
+        match self
+            .receivers
+            .get(&from)
+            .ok_or_else(|| unknown_party(from))?
+            .recv_timeout(timeout)
+        {
+            Ok(frame) => Ok(Some(frame)),
+            Err(RecvTimeoutError::Timeout) => Ok(None),
+            Err(RecvTimeoutError::Disconnected) => Err(closed(from)),
+        }
+    }
+}
+
+/// Streams that can be split into a half for reading and one for writing.
+pub trait DuplexStream: Read + Write + Send + Sized + 'static {
+    fn try_clone(&self) -> io::Result<Self>;
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()>;
+}
+
+impl DuplexStream for TcpStream {
+    fn try_clone(&self) -> io::Result<Self> {
+        TcpStream::try_clone(self)
+    }
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        TcpStream::set_read_timeout(self, timeout)
+    }
+}
+
+#[cfg(unix)]
+impl DuplexStream for std::os::unix::net::UnixStream {
+    fn try_clone(&self) -> io::Result<Self> {
+        std::os::unix::net::UnixStream::try_clone(self)
+    }
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        std::os::unix::net::UnixStream::set_read_timeout(self, timeout)
+    }
+}
+
+/// Transport over one stream per peer. Frames are prefixed with their length.
+///
+/// Writes happen on a background thread per peer, so that parties sending large messages to
+/// each other at the same time do not block on full socket buffers.
+pub struct StreamTransport<S> {
+    party_id: PartyId,
+    readers: BTreeMap<PartyId, S>,
+    writers: BTreeMap<PartyId, Sender<Vec<u8>>>,
+}
+
+impl<S: DuplexStream> StreamTransport<S> {
+    pub fn new(party_id: PartyId, streams: BTreeMap<PartyId, S>) -> Result<Self, PsiError> {
+        let mut readers = BTreeMap::new();
+        let mut writers = BTreeMap::new();
+        for (peer, stream) in streams {
+            let mut writer = stream.try_clone().map_err(|e| io_error(peer, e))?;
+            let (sender, receiver) = channel::<Vec<u8>>();
+            thread::spawn(move || {
+                // ends once the transport is dropped or the peer goes away
+                receiver
+                    .iter()
+                    .try_for_each(|frame| write_frame(&mut writer, &frame))
+            });
+            readers.insert(peer, stream);
+            writers.insert(peer, sender);
+        }
+
+        Ok(StreamTransport {
+            party_id,
+            readers,
+            writers,
+        })
+    }
+}
+
+impl StreamTransport<TcpStream> {
+    /// Connects to every other party of `addresses` over TCP.
+    ///
+    /// `listener` must listen on the party's own address. The party connects to the peers with
+    /// lower ids, retrying while they are not up yet, and accepts the peers with higher ids.
+    pub fn tcp(
+        party_id: PartyId,
+        listener: TcpListener,
+        addresses: &BTreeMap<PartyId, SocketAddr>,
+    ) -> Result<Self, PsiError> {
+        let streams = connect_mesh(
+            party_id,
+            addresses.keys().copied(),
+            || listener.accept().map(|(stream, _)| stream),
+            |peer| TcpStream::connect(addresses[&peer]),
+        )?;
+        StreamTransport::new(party_id, streams)
+    }
+}
+
+#[cfg(unix)]
+impl StreamTransport<std::os::unix::net::UnixStream> {
+    /// Connects to every other party of `paths` over Unix domain sockets, like
+    /// [`StreamTransport::tcp`].
+    pub fn unix(
+        party_id: PartyId,
+        listener: std::os::unix::net::UnixListener,
+        paths: &BTreeMap<PartyId, std::path::PathBuf>,
+    ) -> Result<Self, PsiError> {
+        let streams = connect_mesh(
+            party_id,
+            paths.keys().copied(),
+            || listener.accept().map(|(stream, _)| stream),
+            |peer| std::os::unix::net::UnixStream::connect(&paths[&peer]),
+        )?;
+        StreamTransport::new(party_id, streams)
+    }
+}
+
+impl<S: DuplexStream> StreamTransport<S> {
+    fn reader(&mut self, from: PartyId) -> Result<&mut S, PsiError> {
+        match self.readers.get_mut(&from) {
+            Some(reader) => Ok(reader),
+            // the stream was given up after a timeout
+            None if self.writers.contains_key(&from) => Err(closed(from)),
+            None => Err(unknown_party(from)),
+        }
+    }
+}
+
+impl<S: DuplexStream> Transport for StreamTransport<S> {
+    fn party_id(&self) -> PartyId {
+        self.party_id
+    }
+
+    fn peers(&self) -> Vec<PartyId> {
+        self.writers.keys().copied().collect()
+    }
+
+    fn send(&mut self, to: PartyId, frame: Vec<u8>) -> Result<(), PsiError> {
+        self.writers
+            .get(&to)
+            .ok_or_else(|| unknown_party(to))?
+            .send(frame)
+            .map_err(|_| closed(to))
+    }
+
+    fn recv(&mut self, from: PartyId) -> Result<Vec<u8>, PsiError> {
+        read_frame(self.reader(from)?).map_err(|e| io_error(from, e))
+    }
+
+    fn recv_timeout(
+        &mut self,
+        from: PartyId,
+        timeout: Duration,
+    ) -> Result<Option<Vec<u8>>, PsiError> {
+        let reader = self.reader(from)?;
+        // a zero timeout would mean no timeout at all
+        reader
+            .set_read_timeout(Some(timeout.max(Duration::from_millis(1))))
+            .map_err(|e| io_error(from, e))?;
+        match read_frame(reader) {
+            Err(e)
+                if matches!(
+                    e.kind(),
+                    io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+                ) =>
+            {
+                // part of a frame may have been read, so the stream cannot be read again
+                self.readers.remove(&from);
+                Ok(None)
+            }
+            result => {
+                reader
+                    .set_read_timeout(None)
+                    .map_err(|e| io_error(from, e))?;
+                result.map(Some).map_err(|e| io_error(from, e))
+            }
+        }
+    }
+}
+
+/// Opens one stream to every party in `party_ids` other than `party_id`.
+///
+/// Connections go from the higher to the lower id, and the connecting party announces its id
+/// first.
+fn connect_mesh<S: DuplexStream>(
+    party_id: PartyId,
+    party_ids: impl Iterator<Item = PartyId>,
+    mut accept: impl FnMut() -> io::Result<S>,
+    mut connect: impl FnMut(PartyId) -> io::Result<S>,
+) -> Result<BTreeMap<PartyId, S>, PsiError> {
+    let (lower, higher): (Vec<_>, Vec<_>) = party_ids
+        .filter(|peer| *peer != party_id)
+        .partition(|peer| *peer < party_id);
+
+    let mut streams = BTreeMap::new();
+    for peer in lower {
+        let mut stream = connect_with_retry(|| connect(peer)).map_err(|e| io_error(peer, e))?;
+        stream
+            .write_all(&party_id.to_le_bytes())
+            .map_err(|e| io_error(peer, e))?;
+        streams.insert(peer, stream);
+    }
+    let expected = streams.len() + higher.len();
+    while streams.len() < expected {
+        let mut stream = accept().map_err(|e| io_error(party_id, e))?;
+        let mut id = [0u8; 4];
+        stream
+            .read_exact(&mut id)
+            .map_err(|e| io_error(party_id, e))?;
+        let peer = PartyId::from_le_bytes(id);
+        if !higher.contains(&peer) || streams.contains_key(&peer) {
+            return Err(PsiError::Transport {
+                party_id: peer,
+                reason: "unexpected connection".to_string(),
+            });
+        }
+        streams.insert(peer, stream);
+    }
+
+    Ok(streams)
+}
+
+fn connect_with_retry<S>(mut connect: impl FnMut() -> io::Result<S>) -> io::Result<S> {
+    let mut attempts = 1;
+    loop {
+        match connect() {
+            Ok(stream) => return Ok(stream),
+            Err(e) if attempts >= CONNECT_ATTEMPTS => return Err(e),
+            Err(_) => {
+                attempts += 1;
+                thread::sleep(CONNECT_DELAY);
+            }
+        }
+    }
+}
+
+fn write_frame(writer: &mut impl Write, frame: &[u8]) -> io::Result<()> {
+    writer.write_all(&(frame.len() as u64).to_le_bytes())?;
+    writer.write_all(frame)?;
+    writer.flush()
+}
+
+fn read_frame(reader: &mut impl Read) -> io::Result<Vec<u8>> {
+    let mut length = [0u8; 8];
+    reader.read_exact(&mut length)?;
+    let length = u64::from_le_bytes(length) as usize;
+    if length > MAX_FRAME_LENGTH {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("frame of {length} bytes exceeds the limit of {MAX_FRAME_LENGTH}"),
+        ));
+    }
+
+    let mut frame = Vec::new();
+    reader.take(length as u64).read_to_end(&mut frame)?;
+    if frame.len() != length {
+        return Err(io::Error::new(
+            io::ErrorKind::UnexpectedEof,
+            "stream ended within a frame",
+        ));
+    }
+    Ok(frame)
+}
+
+fn unknown_party(party_id: PartyId) -> PsiError {
+    PsiError::Transport {
+        party_id,
+        reason: "not a party of the session".to_string(),
+    }
+}
+
+fn closed(party_id: PartyId) -> PsiError {
+    PsiError::Transport {
+        party_id,
+        reason: "connection closed".to_string(),
+    }
+}
+
+fn io_error(party_id: PartyId, error: io::Error) -> PsiError {
+    PsiError::Transport {
+        party_id,
+        reason: error.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn frames_round_trip() {
+        let mut buffer = vec![];
+        write_frame(&mut buffer, b"first").unwrap();
+        write_frame(&mut buffer, b"").unwrap();
+
+        let mut reader = buffer.as_slice();
+        assert_eq!(read_frame(&mut reader).unwrap(), b"first");
+        assert_eq!(read_frame(&mut reader).unwrap(), b"");
+        assert!(read_frame(&mut reader).is_err());
+    }
+
+    #[test]
+    fn read_frame_rejects_oversized_frame() {
+        let buffer = (MAX_FRAME_LENGTH as u64 + 1).to_le_bytes();
+
+        let result = read_frame(&mut buffer.as_slice());
+
+        assert_eq!(result.unwrap_err().kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn read_frame_rejects_truncated_frame() {
+        let mut buffer = (MAX_FRAME_LENGTH as u64).to_le_bytes().to_vec();
+        buffer.extend_from_slice(b"short");
+
+        let result = read_frame(&mut buffer.as_slice());
+
+        assert_eq!(result.unwrap_err().kind(), io::ErrorKind::UnexpectedEof);
+    }
+
+    #[test]
+    fn channel_transport_works() {
+        let mut transports = ChannelTransport::mesh(&[3, 5]);
+        let mut b = transports.pop().unwrap();
+        let mut a = transports.pop().unwrap();
+
+        a.send(5, b"hello".to_vec()).unwrap();
+        assert_eq!(b.recv(3).unwrap(), b"hello");
+        assert_eq!(a.peers(), vec![5]);
+        assert!(matches!(
+            a.send(4, vec![]),
+            Err(PsiError::Transport { party_id: 4, .. })
+        ));
+    }
+
+    #[test]
+    fn stream_transport_recv_times_out() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let stream = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (peer_stream, _) = listener.accept().unwrap();
+        let mut a = StreamTransport::new(0, BTreeMap::from([(1, stream)])).unwrap();
+        let mut b = StreamTransport::new(1, BTreeMap::from([(0, peer_stream)])).unwrap();
+
+        b.send(0, b"hello".to_vec()).unwrap();
+        assert_eq!(
+            a.recv_timeout(1, Duration::from_secs(10)).unwrap(),
+            Some(b"hello".to_vec())
+        );
+        assert_eq!(a.recv_timeout(1, Duration::from_millis(50)).unwrap(), None);
+        // the stream is given up after a timeout
+        assert!(matches!(
+            a.recv(1),
+            Err(PsiError::Transport { party_id: 1, .. })
+        ));
+        assert_eq!(a.peers(), vec![1]);
+    }
+}
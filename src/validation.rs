@@ -0,0 +1,536 @@
+//! Checks on the shares that peers send before they are aggregated.
+//!
+//! bfv aggregates whatever it is given, so [`convert_peer_messages`] checks that the polynomials
+//! of the peers' messages have the shape of our own before converting them: the same number of
+//! polynomials, each over the same moduli, with `ring_size` coefficients below their modulus. A
+//! message that fails the check is rejected with [`PsiError::MalformedShare`] before it reaches
+//! bfv. The rounds take converted messages and do not check their polynomials again.
+//!
+//! The norm of the error terms cannot be checked. Every share is masked by a uniformly random
+//! polynomial, the CRS times a secret key or the mask of a ciphertext, so a share with a large
+//! error is indistinguishable from an honest one without the secret of its sender.
+//!
+//! The collective keys are sums of one share per party. A peer that sends back the share of
+//! another party, its negation or any other multiple of it adds nothing of its own secret to the
+//! sum, and in the case of the negation cancels the honest party out of the key altogether.
+//! Shares of honest parties are independent and uniformly distributed, so two of them are
+//! multiples of each other only with negligible probability. Round 1 messages are checked for such
+//! shares while they are converted.
+//!
+//! This only catches a share that is a multiple of a single other share. A peer that waits for
+//! the shares of the others can send a combination of them, such as its own share minus the share
+//! of a victim, which passes the check and still cancels the victim out of the key.
+//! [`round1_commit`](crate::round1_commit) rules that out by committing to the shares before any
+//! of them is revealed.
+//!
+//! bfv only exposes the coefficients of its polynomials through their `*Proto` form, which holds
+//! one byte string per modulus of the RNS basis, with the coefficients bit-packed in
+//! little-endian order at the bit width of the modulus. bfv panics on a `*Proto` of the wrong
+//! shape, so messages from peers go through [`convert_peer_messages`] rather than
+//! [`convert`](crate::convert).
+
+use std::collections::BTreeMap;
+
+use bfv::{BfvParameters, PolyProto};
+use itertools::Itertools;
+use traits::TryFromWithParameters;
+
+use crate::{
+    convert,
+    parameters::bit_length,
+    proto::{
+        MessageRound1Proto, MessageRound1RevealProto, MessageRound2Proto, MessageRound3Proto,
+        MessageRound3RevealProto,
+    },
+    PartyId, PsiContext, PsiError, PsiParameters,
+};
+
+/// Serialized round messages whose shares are checked before they are converted.
+pub trait SharePolys {
+    /// Polynomials of the shares of the message, in a fixed order.
+    fn share_polys(&self) -> Vec<PolyProto>;
+
+    /// Sender of the message if its shares are summed into the collective keys, in which case
+    /// the shares of different parties must not be multiples of each other.
+    fn key_share_sender(&self) -> Option<PartyId> {
+        None
+    }
+}
+
+impl SharePolys for MessageRound1Proto {
+    fn share_polys(&self) -> Vec<PolyProto> {
+        self.share_pk
+            .share
+            .iter()
+            .chain(&self.share_rlk1.shares)
+            .chain(self.share_rtgs.iter().flat_map(|share| &share.shares))
+            .cloned()
+            .collect_vec()
+    }
+
+    fn key_share_sender(&self) -> Option<PartyId> {
+        Some(self.party_id)
+    }
+}
+
+impl SharePolys for MessageRound1RevealProto {
+    fn share_polys(&self) -> Vec<PolyProto> {
+        self.message.share_polys()
+    }
+
+    fn key_share_sender(&self) -> Option<PartyId> {
+        self.message.key_share_sender()
+    }
+}
+
+impl SharePolys for MessageRound2Proto {
+    fn share_polys(&self) -> Vec<PolyProto> {
+        self.share_rlk2
+            .shares
+            .iter()
+            .chain(self.cts.iter().flat_map(|ct| &ct.c))
+            .cloned()
+            .collect_vec()
+    }
+}
+
+impl SharePolys for MessageRound3Proto {
+    fn share_polys(&self) -> Vec<PolyProto> {
+        self.decryption_shares
+            .iter()
+            .flat_map(|share| &share.share)
+            .cloned()
+            .collect_vec()
+    }
+}
+
+impl SharePolys for MessageRound3RevealProto {
+    fn share_polys(&self) -> Vec<PolyProto> {
+        self.message.share_polys()
+    }
+}
+
+/// Checks that the peers' messages have the shape of our own `message` and converts them.
+///
+/// Key shares that are multiples of the share of another party, including our own, are rejected
+/// with [`PsiError::RelatedShare`].
+pub fn convert_peer_messages<P, T>(
+    psi_context: &PsiContext,
+    message: &P,
+    other_messages: &BTreeMap<PartyId, P>,
+) -> Result<BTreeMap<PartyId, T>, PsiError>
+where
+    P: SharePolys,
+    T: TryFromWithParameters<Value = P, Parameters = BfvParameters>,
+{
+    let (own_polys, other_polys) = decode_messages(
+        psi_context.psi_parameters(),
+        message,
+        other_messages,
+        P::share_polys,
+    )?;
+    if let Some(party_id) = message.key_share_sender() {
+        // an echoed share would leave our own secret out of the collective keys
+        check_related_shares(
+            psi_context.psi_parameters(),
+            party_id,
+            own_polys,
+            other_polys,
+        )?;
+    }
+    Ok(other_messages
+        .iter()
+        .map(|(party_id, m)| (*party_id, convert(m, psi_context.bfv_parameters())))
+        .collect())
+}
+
+/// Coefficients of a polynomial, one row per modulus of its RNS basis.
+pub(crate) type Coefficients = Vec<Vec<u64>>;
+
+/// Decodes the coefficients of `poly`, whose rows use the first moduli of `moduli`, and checks
+/// that they are below their modulus.
+pub(crate) fn decode_poly(
+    poly: &PolyProto,
+    moduli: &[u64],
+    ring_size: usize,
+) -> Result<Coefficients, String> {
+    if poly.coefficients.is_empty() || poly.coefficients.len() > moduli.len() {
+        return Err(format!(
+            "polynomial has {} moduli, expected 1 to {}",
+            poly.coefficients.len(),
+            moduli.len()
+        ));
+    }
+
+    poly.coefficients
+        .iter()
+        .zip(moduli.iter().copied())
+        .map(|(bytes, q)| {
+            let row = unpack(bytes, bit_length(q), ring_size).ok_or_else(|| {
+                format!(
+                    "polynomial holds {} bytes for modulus {q}, expected {}",
+                    bytes.len(),
+                    packed_length(bit_length(q), ring_size)
+                )
+            })?;
+            match row.iter().find_position(|c| **c >= q) {
+                Some((index, c)) => Err(format!(
+                    "coefficient {c} at index {index} is not below its modulus {q}"
+                )),
+                None => Ok(row),
+            }
+        })
+        .collect()
+}
+
+fn packed_length(bits: usize, count: usize) -> usize {
+    (bits * count).div_ceil(8)
+}
+
+/// Reads `count` values of `bits` bits each from `bytes`, least significant bit first.
+fn unpack(bytes: &[u8], bits: usize, count: usize) -> Option<Vec<u64>> {
+    if bytes.len() != packed_length(bits, count) {
+        return None;
+    }
+
+    let mut bytes = bytes.iter();
+    let mut buffer = 0u128;
+    let mut buffered_bits = 0;
+    (0..count)
+        .map(|_| {
+            while buffered_bits < bits {
+                buffer |= (*bytes.next()? as u128) << buffered_bits;
+                buffered_bits += 8;
+            }
+            let value = (buffer & ((1u128 << bits) - 1)) as u64;
+            buffer >>= bits;
+            buffered_bits -= bits;
+            Some(value)
+        })
+        .collect()
+}
+
+/// Whether `b = c * a` for some constant `c` modulo every modulus, which includes `b = a` and
+/// `b = -a`.
+pub(crate) fn polys_related(a: &Coefficients, b: &Coefficients, moduli: &[u64]) -> bool {
+    a.len() == b.len()
+        && a.iter()
+            .zip(b)
+            .zip(moduli)
+            .all(|((a, b), q)| rows_related(a, b, *q))
+}
+
+fn rows_related(a: &[u64], b: &[u64], q: u64) -> bool {
+    let Some(j) = a.iter().position(|a| *a % q != 0) else {
+        return b.iter().all(|b| *b % q == 0);
+    };
+    // q is prime, so every coefficient other than zero is invertible
+    let c = mul_mod(b[j], pow_mod(a[j], q - 2, q), q);
+    a.iter().zip(b).all(|(a, b)| mul_mod(c, *a, q) == *b % q)
+}
+
+fn mul_mod(a: u64, b: u64, q: u64) -> u64 {
+    ((a as u128 * b as u128) % q as u128) as u64
+}
+
+fn pow_mod(mut base: u64, mut exponent: u64, q: u64) -> u64 {
+    let mut result = 1 % q;
+    base %= q;
+    while exponent > 0 {
+        if exponent & 1 == 1 {
+            result = mul_mod(result, base, q);
+        }
+        base = mul_mod(base, base, q);
+        exponent >>= 1;
+    }
+    result
+}
+
+/// Rejects key shares that are multiples of the shares of another party, including our own.
+///
+/// Every polynomial of a share is compared with the polynomial at the same position in the
+/// shares of all other parties. Of two related shares, the one of the peer with the higher id
+/// is blamed, unless that is our own.
+fn check_related_shares(
+    psi_parameters: &PsiParameters,
+    party_id: PartyId,
+    own_polys: Vec<Coefficients>,
+    mut polys: BTreeMap<PartyId, Vec<Coefficients>>,
+) -> Result<(), PsiError> {
+    let moduli = psi_parameters.key_moduli();
+    polys.insert(party_id, own_polys);
+
+    for ((a_id, a_polys), (b_id, b_polys)) in polys.iter().tuple_combinations() {
+        if a_polys
+            .iter()
+            .zip(b_polys)
+            .any(|(a, b)| polys_related(a, b, &moduli))
+        {
+            let (blamed, related_to) = if *b_id == party_id {
+                (*a_id, *b_id)
+            } else {
+                (*b_id, *a_id)
+            };
+            return Err(PsiError::RelatedShare {
+                party_id: blamed,
+                related_to,
+            });
+        }
+    }
+
+    Ok(())
+}
+
+/// Decodes the polynomials of our own message and of the peers' messages, which must have the
+/// shape of our own.
+fn decode_messages<M>(
+    psi_parameters: &PsiParameters,
+    message: &M,
+    other_messages: &BTreeMap<PartyId, M>,
+    polys: impl Fn(&M) -> Vec<PolyProto>,
+) -> Result<(Vec<Coefficients>, BTreeMap<PartyId, Vec<Coefficients>>), PsiError> {
+    let moduli = psi_parameters.key_moduli();
+    let ring_size = psi_parameters.ring_size();
+    let own_polys = polys(message);
+
+    let other_polys = other_messages
+        .iter()
+        .map(|(id, m)| {
+            let malformed = |reason| PsiError::MalformedShare {
+                party_id: *id,
+                reason,
+            };
+            let polys = polys(m);
+            if polys.len() != own_polys.len() {
+                return Err(malformed(format!(
+                    "expected {} polynomials, found {}",
+                    own_polys.len(),
+                    polys.len()
+                )));
+            }
+            let polys = polys
+                .iter()
+                .zip(&own_polys)
+                .enumerate()
+                .map(|(i, (poly, own_poly))| {
+                    // the moduli of a polynomial give its level
+                    if poly.coefficients.len() != own_poly.coefficients.len() {
+                        return Err(format!(
+                            "polynomial {i} has {} moduli, expected {}",
+                            poly.coefficients.len(),
+                            own_poly.coefficients.len()
+                        ));
+                    }
+                    decode_poly(poly, &moduli, ring_size)
+                })
+                .collect::<Result<Vec<_>, _>>()
+                .map_err(malformed)?;
+            Ok((*id, polys))
+        })
+        .collect::<Result<_, PsiError>>()?;
+
+    // our own message went through bfv, so it only fails to decode if bfv packs its polynomials
+    // differently than we assume
+    let own_polys = own_polys
+        .iter()
+        .map(|poly| decode_poly(poly, &moduli, ring_size))
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|reason| {
+            PsiError::InvalidParameters(format!("own message does not decode: {reason}"))
+        })?;
+    Ok((own_polys, other_polys))
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::{thread_rng, Rng};
+
+    use super::*;
+    use crate::{crs::run_coin_toss, gen_keys, MessageRound1, PsiMode};
+
+    fn pack(values: &[u64], bits: usize) -> Vec<u8> {
+        let mut bytes = vec![0u8; packed_length(bits, values.len())];
+        values.iter().enumerate().for_each(|(i, value)| {
+            (0..bits).for_each(|bit| {
+                let position = i * bits + bit;
+                bytes[position / 8] |= (((value >> bit) & 1) as u8) << (position % 8);
+            })
+        });
+        bytes
+    }
+
+    fn random_poly(moduli: &[u64], ring_size: usize) -> Coefficients {
+        let mut rng = thread_rng();
+        moduli
+            .iter()
+            .map(|q| (0..ring_size).map(|_| rng.gen_range(0..*q)).collect_vec())
+            .collect_vec()
+    }
+
+    #[test]
+    fn decode_poly_works() {
+        let psi_parameters = PsiParameters::small();
+        let moduli = psi_parameters.key_moduli();
+        let ring_size = psi_parameters.ring_size();
+        let coefficients = random_poly(&moduli, ring_size);
+
+        let poly = PolyProto {
+            coefficients: coefficients
+                .iter()
+                .zip(&moduli)
+                .map(|(row, q)| pack(row, bit_length(*q)))
+                .collect_vec(),
+        };
+
+        assert_eq!(decode_poly(&poly, &moduli, ring_size), Ok(coefficients));
+        assert!(decode_poly(&poly, &moduli[..1], ring_size).is_err());
+        assert!(decode_poly(&poly, &moduli, ring_size / 2).is_err());
+    }
+
+    #[test]
+    fn decode_poly_reads_bfv_polys() {
+        let psi_parameters = PsiParameters::small();
+        let psi_context = PsiContext::new(psi_parameters.clone());
+        let moduli = psi_parameters.key_moduli();
+        let (_, message) = gen_keys(&psi_context, &run_coin_toss(&[0]), PsiMode::Intersection, 0);
+        let message: MessageRound1Proto = convert(&message, psi_context.bfv_parameters());
+
+        message.share_polys().iter().for_each(|poly| {
+            let coefficients = decode_poly(poly, &moduli, psi_parameters.ring_size()).unwrap();
+            // packing the coefficients again must give back the bytes of bfv
+            let packed = coefficients
+                .iter()
+                .zip(&moduli)
+                .map(|(row, q)| pack(row, bit_length(*q)))
+                .collect_vec();
+            assert_eq!(packed, poly.coefficients);
+        });
+    }
+
+    #[test]
+    fn decode_poly_rejects_coefficients_above_modulus() {
+        let moduli = PsiParameters::small().key_moduli();
+        let mut coefficients = random_poly(&moduli[..1], 16);
+        coefficients[0][3] = moduli[0];
+
+        let poly = PolyProto {
+            coefficients: vec![pack(&coefficients[0], bit_length(moduli[0]))],
+        };
+
+        assert_eq!(
+            decode_poly(&poly, &moduli, 16),
+            Err(format!(
+                "coefficient {} at index 3 is not below its modulus {}",
+                moduli[0], moduli[0]
+            ))
+        );
+    }
+
+    #[test]
+    fn polys_related_works() {
+        let moduli = PsiParameters::small().key_moduli();
+        let a = random_poly(&moduli, 64);
+        let scaled = |c: u64| {
+            a.iter()
+                .zip(&moduli)
+                .map(|(row, q)| row.iter().map(|v| mul_mod(*v, c % q, *q)).collect_vec())
+                .collect_vec()
+        };
+        let negated = a
+            .iter()
+            .zip(&moduli)
+            .map(|(row, q)| row.iter().map(|v| (q - v) % q).collect_vec())
+            .collect_vec();
+
+        assert!(polys_related(&a, &a, &moduli));
+        assert!(polys_related(&a, &negated, &moduli));
+        assert!(polys_related(&a, &scaled(12345), &moduli));
+        assert!(!polys_related(&a, &random_poly(&moduli, 64), &moduli));
+    }
+
+    #[test]
+    fn convert_peer_messages_rejects_echoed_share() {
+        let psi_context = PsiContext::new(PsiParameters::small());
+        let crs = run_coin_toss(&[0, 1, 2]);
+        let mut messages = (0..3)
+            .map(|party_id| {
+                let (_, message) = gen_keys(&psi_context, &crs, PsiMode::Intersection, party_id);
+                let message: MessageRound1Proto = convert(&message, psi_context.bfv_parameters());
+                (party_id, message)
+            })
+            .collect::<BTreeMap<_, _>>();
+        let message = messages.remove(&0).unwrap();
+
+        // party 2 reflects the share of party 0 back at it
+        messages.get_mut(&2).unwrap().share_pk = message.share_pk.clone();
+        let result = convert_peer_messages::<_, MessageRound1>(&psi_context, &message, &messages);
+
+        assert_eq!(
+            result.err(),
+            Some(PsiError::RelatedShare {
+                party_id: 2,
+                related_to: 0
+            })
+        );
+    }
+
+    #[test]
+    fn decode_messages_rejects_misshapen_polys() {
+        let psi_parameters = PsiParameters::small();
+        let moduli = psi_parameters.key_moduli();
+        let ring_size = psi_parameters.ring_size();
+        let encode = |coefficients: &Coefficients| PolyProto {
+            coefficients: coefficients
+                .iter()
+                .zip(&moduli)
+                .map(|(row, q)| pack(row, bit_length(*q)))
+                .collect_vec(),
+        };
+        let message = vec![
+            encode(&random_poly(&moduli, ring_size)),
+            encode(&random_poly(&moduli[..2], ring_size)),
+        ];
+        let mut overflowing = random_poly(&moduli[..2], ring_size);
+        overflowing[1][0] = moduli[1];
+
+        [
+            (
+                vec![encode(&random_poly(&moduli, ring_size))],
+                "expected 2 polynomials, found 1".to_string(),
+            ),
+            (
+                vec![
+                    encode(&random_poly(&moduli, ring_size)),
+                    encode(&random_poly(&moduli[..1], ring_size)),
+                ],
+                "polynomial 1 has 1 moduli, expected 2".to_string(),
+            ),
+            (
+                vec![
+                    encode(&random_poly(&moduli, ring_size)),
+                    encode(&overflowing),
+                ],
+                format!(
+                    "coefficient {} at index 0 is not below its modulus {}",
+                    moduli[1], moduli[1]
+                ),
+            ),
+        ]
+        .into_iter()
+        .for_each(|(other_message, reason)| {
+            let result = decode_messages(
+                &psi_parameters,
+                &message,
+                &BTreeMap::from([(1, message.clone()), (2, other_message)]),
+                |m| m.clone(),
+            );
+            assert_eq!(
+                result.err(),
+                Some(PsiError::MalformedShare {
+                    party_id: 2,
+                    reason
+                })
+            );
+        });
+    }
+}
@@ -0,0 +1,349 @@
+use std::collections::BTreeMap;
+
+use serde::{de::DeserializeOwned, Serialize};
+use wasm_bindgen::{prelude::wasm_bindgen, JsCast, JsValue};
+
+use crate::{
+    check_input, convert, convert_peer_messages, crs_commit, crs_derive, crs_reveal, gen_keys,
+    proto::{MessageRound1Proto, MessageRound2Proto, MessageRound3Proto},
+    round1, round2, round3, round3_cardinality, round3_indices, MessageCrsCommit, MessageCrsReveal,
+    MessageRound1, MessageRound2, MessageRound3, PartyId, PsiContext, PsiError, PsiKeys, PsiMode,
+    PsiParameters, SharePolys, StateCrsCommit, StateCrsReveal, StateRound2, StateRound3,
+};
+
+#[wasm_bindgen(typescript_custom_section)]
+const TS_TYPES: &str = r#"
+export interface PsiParameters {
+    ring_size: number;
+    ciphertext_moduli: number[];
+    extension_moduli: number[];
+    plaintext_modulus: number;
+    special_moduli: number[];
+    smudging_bits: number;
+    input_bound: number;
+}
+export interface MessageCrsCommit { commitment: number[]; }
+export interface MessageCrsReveal { seed: number[]; }
+export type PsiMode = "Intersection" | "Cardinality" | { Threshold: { k: number } };
+export interface MessageRound1 { party_id: number; session_id: number[]; parameters: PsiParameters; mode: PsiMode; share_pk: unknown; share_rlk1: unknown; share_rtgs: unknown[]; }
+export interface MessageRound2 { domain_size: number; share_rlk2: unknown; cts: unknown[]; }
+export interface MessageRound3 { decryption_shares: unknown[]; }
+"#;
+
+#[wasm_bindgen]
+extern "C" {
+    #[wasm_bindgen(typescript_type = "PsiParameters")]
+    pub type JsPsiParameters;
+    #[wasm_bindgen(typescript_type = "MessageCrsCommit")]
+    pub type JsMessageCrsCommit;
+    #[wasm_bindgen(typescript_type = "Map<number, MessageCrsCommit>")]
+    pub type JsMessagesCrsCommit;
+    #[wasm_bindgen(typescript_type = "MessageCrsReveal")]
+    pub type JsMessageCrsReveal;
+    #[wasm_bindgen(typescript_type = "Map<number, MessageCrsReveal>")]
+    pub type JsMessagesCrsReveal;
+    #[wasm_bindgen(typescript_type = "PsiMode")]
+    pub type JsPsiMode;
+    #[wasm_bindgen(typescript_type = "MessageRound1")]
+    pub type JsMessageRound1;
+    #[wasm_bindgen(typescript_type = "Map<number, MessageRound1>")]
+    pub type JsMessagesRound1;
+    #[wasm_bindgen(typescript_type = "MessageRound2")]
+    pub type JsMessageRound2;
+    #[wasm_bindgen(typescript_type = "Map<number, MessageRound2>")]
+    pub type JsMessagesRound2;
+    #[wasm_bindgen(typescript_type = "MessageRound3")]
+    pub type JsMessageRound3;
+    #[wasm_bindgen(typescript_type = "Map<number, MessageRound3>")]
+    pub type JsMessagesRound3;
+}
+
+/// Returns the `small`, `medium` or `large` parameter preset.
+#[wasm_bindgen]
+pub fn psi_parameters_bindgen(preset: &str) -> Result<JsPsiParameters, PsiError> {
+    let psi_parameters = PsiParameters::preset(preset)
+        .ok_or_else(|| PsiError::InvalidParameters(format!("unknown preset {preset}")))?;
+    to_js(&psi_parameters)
+}
+
+/// One party of a session, driven from JavaScript one step at a time.
+///
+/// The keys and round states of the party stay in wasm memory. Every step takes the messages
+/// of the peers and returns only the party's message for them, and the last step returns the
+/// output of the session. The keys are destroyed as soon as `round2` no longer needs them.
+#[wasm_bindgen]
+pub struct PsiSession {
+    party_id: PartyId,
+    mode: PsiMode,
+    psi_context: PsiContext,
+    stage: Stage,
+}
+
+/// The step the session is in, with the party's state and its own message of the previous
+/// step.
+enum Stage {
+    CrsCommit,
+    CrsReveal {
+        state: StateCrsCommit,
+        message: MessageCrsCommit,
+    },
+    GenKeys {
+        state: StateCrsReveal,
+        message: MessageCrsReveal,
+    },
+    Round1 {
+        psi_keys: PsiKeys,
+        message: MessageRound1,
+    },
+    Round2 {
+        psi_keys: PsiKeys,
+        state: StateRound2,
+        message: MessageRound2,
+    },
+    Round3 {
+        state: StateRound3,
+        message: MessageRound3,
+    },
+    Finished,
+}
+
+impl Stage {
+    fn name(&self) -> &'static str {
+        match self {
+            Stage::CrsCommit => "crs_commit",
+            Stage::CrsReveal { .. } => "crs_reveal",
+            Stage::GenKeys { .. } => "gen_keys",
+            Stage::Round1 { .. } => "round1",
+            Stage::Round2 { .. } => "round2",
+            Stage::Round3 { .. } => "round3",
+            Stage::Finished => "nothing",
+        }
+    }
+}
+
+#[wasm_bindgen]
+impl PsiSession {
+    #[wasm_bindgen(constructor)]
+    pub fn new(
+        parameters: JsPsiParameters,
+        mode: JsPsiMode,
+        party_id: PartyId,
+    ) -> Result<PsiSession, PsiError> {
+        Ok(PsiSession {
+            party_id,
+            mode: from_js(mode.into(), "mode")?,
+            psi_context: PsiContext::new(from_js(parameters.into(), "parameters")?),
+            stage: Stage::CrsCommit,
+        })
+    }
+
+    /// Starts the coin toss of the session CRS and returns the commitment for the peers.
+    pub fn crs_commit(&mut self) -> Result<JsMessageCrsCommit, PsiError> {
+        self.take_stage("crs_commit")?;
+
+        let (state, message) = crs_commit(self.party_id);
+
+        let output = to_js(&message)?;
+        self.stage = Stage::CrsReveal { state, message };
+        Ok(output)
+    }
+
+    /// Returns the party's seed once the commitments of all peers are in.
+    pub fn crs_reveal(
+        &mut self,
+        other_messages: JsMessagesCrsCommit,
+    ) -> Result<JsMessageCrsReveal, PsiError> {
+        let other_messages = from_js(other_messages.into(), "other_messages")?;
+        let Stage::CrsReveal { state, message } = self.take_stage("crs_reveal")? else {
+            unreachable!()
+        };
+
+        let (state, message) = crs_reveal(state, message, other_messages)?;
+
+        let output = to_js(&message)?;
+        self.stage = Stage::GenKeys { state, message };
+        Ok(output)
+    }
+
+    /// Derives the session CRS from the peers' seeds, generates the party's keys and returns
+    /// its shares of the collective keys.
+    pub fn gen_keys(
+        &mut self,
+        other_messages: JsMessagesCrsReveal,
+    ) -> Result<JsMessageRound1, PsiError> {
+        let other_messages = from_js(other_messages.into(), "other_messages")?;
+        let Stage::GenKeys { state, message } = self.take_stage("gen_keys")? else {
+            unreachable!()
+        };
+
+        let crs = crs_derive(state, message, other_messages)?;
+        let (psi_keys, message) = gen_keys(&self.psi_context, &crs, self.mode, self.party_id);
+
+        let output = to_js(&convert::<_, MessageRound1Proto>(
+            &message,
+            self.psi_context.bfv_parameters(),
+        ))?;
+        self.stage = Stage::Round1 { psi_keys, message };
+        Ok(output)
+    }
+
+    /// Encrypts the party's bit vector. An invalid bit vector leaves the session in round 1, so
+    /// that the call can be retried with a fixed one.
+    pub fn round1(
+        &mut self,
+        other_messages: JsMessagesRound1,
+        bit_vector: &[u32],
+    ) -> Result<JsMessageRound2, PsiError> {
+        check_input(self.psi_context.psi_parameters(), bit_vector)?;
+        let Stage::Round1 { message, .. } = self.current_stage("round1")? else {
+            unreachable!()
+        };
+        let message: MessageRound1Proto = convert(message, self.psi_context.bfv_parameters());
+        let other_messages = self.peer_messages(&message, other_messages.into())?;
+        let Stage::Round1 { psi_keys, message } = self.take_stage("round1")? else {
+            unreachable!()
+        };
+
+        let (state, message) = round1(
+            &self.psi_context,
+            &psi_keys,
+            message,
+            other_messages,
+            bit_vector,
+        )?;
+
+        let output = to_js(&convert::<_, MessageRound2Proto>(
+            &message,
+            self.psi_context.bfv_parameters(),
+        ))?;
+        self.stage = Stage::Round2 {
+            psi_keys,
+            state,
+            message,
+        };
+        Ok(output)
+    }
+
+    /// Computes the output of the session under encryption and returns the party's decryption
+    /// shares. Destroys the party's keys.
+    pub fn round2(
+        &mut self,
+        other_messages: JsMessagesRound2,
+    ) -> Result<JsMessageRound3, PsiError> {
+        let Stage::Round2 { message, .. } = self.current_stage("round2")? else {
+            unreachable!()
+        };
+        let message: MessageRound2Proto = convert(message, self.psi_context.bfv_parameters());
+        let other_messages = self.peer_messages(&message, other_messages.into())?;
+        let Stage::Round2 {
+            psi_keys,
+            state,
+            message,
+        } = self.take_stage("round2")?
+        else {
+            unreachable!()
+        };
+
+        let (state, message) =
+            round2(&self.psi_context, &psi_keys, state, message, other_messages)?;
+        psi_keys.destroy();
+
+        let output = to_js(&convert::<_, MessageRound3Proto>(
+            &message,
+            self.psi_context.bfv_parameters(),
+        ))?;
+        self.stage = Stage::Round3 { state, message };
+        Ok(output)
+    }
+
+    /// Decrypts the output of the session, one value per slot of the domain, or the
+    /// intersection size alone in cardinality mode.
+    pub fn round3(&mut self, other_messages: JsMessagesRound3) -> Result<Vec<u32>, PsiError> {
+        let (state, message, other_messages) = self.take_round3(other_messages)?;
+
+        round3(&self.psi_context, state, message, other_messages)
+    }
+
+    /// Like `round3`, returning the indices of the slots in the intersection.
+    pub fn round3_indices(
+        &mut self,
+        other_messages: JsMessagesRound3,
+    ) -> Result<Vec<usize>, PsiError> {
+        let (state, message, other_messages) = self.take_round3(other_messages)?;
+
+        round3_indices(&self.psi_context, state, message, other_messages)
+    }
+
+    /// Like `round3` for sessions in cardinality mode, returning the intersection size.
+    pub fn round3_cardinality(
+        &mut self,
+        other_messages: JsMessagesRound3,
+    ) -> Result<u32, PsiError> {
+        let (state, message, other_messages) = self.take_round3(other_messages)?;
+
+        round3_cardinality(&self.psi_context, state, message, other_messages)
+    }
+}
+
+impl PsiSession {
+    /// Returns the state of the session if `step` is the next one.
+    fn current_stage(&self, step: &str) -> Result<&Stage, PsiError> {
+        if self.stage.name() != step {
+            return Err(PsiError::OutOfOrder {
+                called: step.to_string(),
+                expected: self.stage.name().to_string(),
+            });
+        }
+        Ok(&self.stage)
+    }
+
+    /// Takes the state of the session out if `step` is the next one. A step that fails after
+    /// that leaves the session finished.
+    fn take_stage(&mut self, step: &str) -> Result<Stage, PsiError> {
+        self.current_stage(step)?;
+        Ok(std::mem::replace(&mut self.stage, Stage::Finished))
+    }
+
+    /// Takes the state of round 3 out, once the peers' messages have been checked.
+    fn take_round3(
+        &mut self,
+        other_messages: JsMessagesRound3,
+    ) -> Result<(StateRound3, MessageRound3, BTreeMap<PartyId, MessageRound3>), PsiError> {
+        let Stage::Round3 { message, .. } = self.current_stage("round3")? else {
+            unreachable!()
+        };
+        let message: MessageRound3Proto = convert(message, self.psi_context.bfv_parameters());
+        let other_messages = self.peer_messages(&message, other_messages.into())?;
+        let Stage::Round3 { state, message } = self.take_stage("round3")? else {
+            unreachable!()
+        };
+        Ok((state, message, other_messages))
+    }
+
+    /// Reads the peers' messages and checks them against our own `message` before converting
+    /// them.
+    fn peer_messages<P, T>(
+        &self,
+        message: &P,
+        other_messages: JsValue,
+    ) -> Result<BTreeMap<PartyId, T>, PsiError>
+    where
+        P: DeserializeOwned + SharePolys,
+        T: traits::TryFromWithParameters<Value = P, Parameters = bfv::BfvParameters>,
+    {
+        let other_messages: BTreeMap<PartyId, P> = from_js(other_messages, "other_messages")?;
+        convert_peer_messages(&self.psi_context, message, &other_messages)
+    }
+}
+
+fn from_js<T: DeserializeOwned>(value: JsValue, name: &str) -> Result<T, PsiError> {
+    serde_wasm_bindgen::from_value(value)
+        .map_err(|e| PsiError::Deserialization(format!("{name}: {e}")))
+}
+
+/// Serializes `value` into a JS object and tags it with the TS type of the export.
+fn to_js<T: Serialize, U: JsCast>(value: &T) -> Result<U, PsiError> {
+    serde_wasm_bindgen::to_value(value)
+        .map(JsCast::unchecked_into)
+        .map_err(|e| PsiError::Serialization(format!("output: {e}")))
+}